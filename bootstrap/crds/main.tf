@@ -3,9 +3,28 @@ resource "kubernetes_manifest" "customresourcedefinition_dbsyncports_demeter_run
     "apiVersion" = "apiextensions.k8s.io/v1"
     "kind" = "CustomResourceDefinition"
     "metadata" = {
+      "annotations" = {
+        "cert-manager.io/inject-ca-from" = "ext-dbsync-m1/operator-webhook"
+      }
       "name" = "dbsyncports.demeter.run"
     }
     "spec" = {
+      "conversion" = {
+        "strategy" = "Webhook"
+        "webhook" = {
+          "clientConfig" = {
+            "service" = {
+              "name" = "operator-webhook"
+              "namespace" = "ext-dbsync-m1"
+              "path" = "/convert"
+              "port" = 9443
+            }
+          }
+          "conversionReviewVersions" = [
+            "v1",
+          ]
+        }
+      }
       "group" = "demeter.run"
       "names" = {
         "categories" = [
@@ -20,6 +39,274 @@ resource "kubernetes_manifest" "customresourcedefinition_dbsyncports_demeter_run
       }
       "scope" = "Namespaced"
       "versions" = [
+        {
+          "additionalPrinterColumns" = [
+            {
+              "jsonPath" = ".spec.network"
+              "name" = "Network"
+              "type" = "string"
+            },
+            {
+              "jsonPath" = ".spec.throughputTier"
+              "name" = "Throughput Tier"
+              "type" = "integer"
+            },
+            {
+              "jsonPath" = ".status.username"
+              "name" = "Username"
+              "type" = "string"
+            },
+            {
+              "jsonPath" = ".status.password"
+              "name" = "Password"
+              "type" = "string"
+            },
+            {
+              "jsonPath" = ".status.conditions[?(@.type==\"ChainSynced\")].status"
+              "name" = "Synced"
+              "type" = "string"
+            },
+          ]
+          "name" = "v1beta1"
+          "schema" = {
+            "openAPIV3Schema" = {
+              "description" = "Auto-generated derived type for DbSyncPortSpec via `CustomResource`"
+              "properties" = {
+                "spec" = {
+                  "properties" = {
+                    "accessProfile" = {
+                      "default" = "standard"
+                      "enum" = [
+                        "standard",
+                        "readOnly",
+                      ]
+                      "type" = "string"
+                    }
+                    "credentialTtl" = {
+                      "description" = "Lifetime of the primary password in hours or days, e.g. `720h` or `30d`. The password is renewed when a third of it is left."
+                      "nullable" = true
+                      "pattern" = "^[1-9][0-9]*(h|d)$"
+                      "type" = "string"
+                    }
+                    "credentialsSecretRef" = {
+                      "description" = "Secret in the namespace of the port with the `password` and optionally the `username`"
+                      "nullable" = true
+                      "properties" = {
+                        "name" = {
+                          "type" = "string"
+                        }
+                      }
+                      "required" = [
+                        "name",
+                      ]
+                      "type" = "object"
+                    }
+                    "expiresAt" = {
+                      "description" = "Expiry of the primary credential, the role can't log in afterwards"
+                      "format" = "date-time"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "network" = {
+                      "maxLength" = 63
+                      "minLength" = 1
+                      "pattern" = "^[a-z0-9]([-a-z0-9]*[a-z0-9])?$"
+                      "type" = "string"
+                    }
+                    "projectRef" = {
+                      "description" = "Project the usage of the port is reported under when its namespace doesn't belong to one"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "secondaryCredential" = {
+                      "description" = "A second credential valid at the same time as the primary one, for rollovers"
+                      "nullable" = true
+                      "properties" = {
+                        "credentialsSecretRef" = {
+                          "description" = "Secret with the `password` and optionally the `username`, generated when not set"
+                          "nullable" = true
+                          "properties" = {
+                            "name" = {
+                              "type" = "string"
+                            }
+                          }
+                          "required" = [
+                            "name",
+                          ]
+                          "type" = "object"
+                        }
+                        "expiresAt" = {
+                          "format" = "date-time"
+                          "nullable" = true
+                          "type" = "string"
+                        }
+                      }
+                      "type" = "object"
+                    }
+                    "throughputTier" = {
+                      "format" = "uint32"
+                      "maximum" = 3.0
+                      "minimum" = 0.0
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "username" = {
+                      "maxLength" = 63
+                      "minLength" = 1
+                      "nullable" = true
+                      "pattern" = "^[a-z_][a-z0-9_]*$"
+                      "type" = "string"
+                    }
+                  }
+                  "required" = [
+                    "network",
+                  ]
+                  "type" = "object"
+                }
+                "status" = {
+                  "nullable" = true
+                  "properties" = {
+                    "conditions" = {
+                      "items" = {
+                        "properties" = {
+                          "lastTransitionTime" = {
+                            "type" = "string"
+                          }
+                          "message" = {
+                            "default" = ""
+                            "type" = "string"
+                          }
+                          "reason" = {
+                            "type" = "string"
+                          }
+                          "status" = {
+                            "type" = "string"
+                          }
+                          "type" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "lastTransitionTime",
+                          "reason",
+                          "status",
+                          "type",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "credentialsExpireAt" = {
+                      "description" = "When the primary password expires, only set with a credentialTtl"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "group" = {
+                      "description" = "Role holding the grants, the credentials of the port are its members"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "host" = {
+                      "description" = "Host to connect to, the public host of the DbSyncNetwork"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "network" = {
+                      "description" = "Network the user is provisioned on, it differs from the spec while the port is migrating"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "password" = {
+                      "type" = "string"
+                    }
+                    "reconcileAt" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "secondary" = {
+                      "nullable" = true
+                      "properties" = {
+                        "password" = {
+                          "type" = "string"
+                        }
+                        "username" = {
+                          "type" = "string"
+                        }
+                      }
+                      "required" = [
+                        "password",
+                        "username",
+                      ]
+                      "type" = "object"
+                    }
+                    "stats" = {
+                      "nullable" = true
+                      "properties" = {
+                        "activeQueries" = {
+                          "format" = "int64"
+                          "type" = "integer"
+                        }
+                        "connections" = {
+                          "format" = "int64"
+                          "type" = "integer"
+                        }
+                        "longestQuerySeconds" = {
+                          "format" = "double"
+                          "type" = "number"
+                        }
+                        "recentQuerySeconds" = {
+                          "format" = "double"
+                          "type" = "number"
+                        }
+                        "sampledAt" = {
+                          "type" = "string"
+                        }
+                      }
+                      "required" = [
+                        "activeQueries",
+                        "connections",
+                        "longestQuerySeconds",
+                        "recentQuerySeconds",
+                        "sampledAt",
+                      ]
+                      "type" = "object"
+                    }
+                    "syncLagSeconds" = {
+                      "format" = "int64"
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "throughputTier" = {
+                      "description" = "Tier the port is metered at, it differs from the spec while a higher tier is over the quota"
+                      "format" = "uint32"
+                      "minimum" = 0.0
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "username" = {
+                      "type" = "string"
+                    }
+                  }
+                  "required" = [
+                    "password",
+                    "username",
+                  ]
+                  "type" = "object"
+                }
+              }
+              "required" = [
+                "spec",
+              ]
+              "title" = "DbSyncPort"
+              "type" = "object"
+            }
+          }
+          "served" = true
+          "storage" = true
+          "subresources" = {
+            "status" = {}
+          }
+        },
         {
           "additionalPrinterColumns" = [
             {
@@ -42,6 +329,11 @@ resource "kubernetes_manifest" "customresourcedefinition_dbsyncports_demeter_run
               "name" = "Password"
               "type" = "string"
             },
+            {
+              "jsonPath" = ".status.conditions[?(@.type==\"ChainSynced\")].status"
+              "name" = "Synced"
+              "type" = "string"
+            },
           ]
           "name" = "v1alpha1"
           "schema" = {
@@ -51,18 +343,26 @@ resource "kubernetes_manifest" "customresourcedefinition_dbsyncports_demeter_run
                 "spec" = {
                   "properties" = {
                     "network" = {
+                      "maxLength" = 63
+                      "minLength" = 1
+                      "pattern" = "^[a-z0-9]([-a-z0-9]*[a-z0-9])?$"
                       "type" = "string"
                     }
                     "password" = {
+                      "minLength" = 8
                       "nullable" = true
                       "type" = "string"
                     }
                     "throughputTier" = {
                       "nullable" = true
+                      "pattern" = "^[0-3]$"
                       "type" = "string"
                     }
                     "username" = {
+                      "maxLength" = 63
+                      "minLength" = 1
                       "nullable" = true
+                      "pattern" = "^[a-z_][a-z0-9_]*$"
                       "type" = "string"
                     }
                   }
@@ -74,9 +374,123 @@ resource "kubernetes_manifest" "customresourcedefinition_dbsyncports_demeter_run
                 "status" = {
                   "nullable" = true
                   "properties" = {
+                    "conditions" = {
+                      "items" = {
+                        "properties" = {
+                          "lastTransitionTime" = {
+                            "type" = "string"
+                          }
+                          "message" = {
+                            "default" = ""
+                            "type" = "string"
+                          }
+                          "reason" = {
+                            "type" = "string"
+                          }
+                          "status" = {
+                            "type" = "string"
+                          }
+                          "type" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "lastTransitionTime",
+                          "reason",
+                          "status",
+                          "type",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "credentialsExpireAt" = {
+                      "description" = "When the primary password expires, only set with a credentialTtl"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "group" = {
+                      "description" = "Role holding the grants, the credentials of the port are its members"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "host" = {
+                      "description" = "Host to connect to, the public host of the DbSyncNetwork"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "network" = {
+                      "description" = "Network the user is provisioned on, it differs from the spec while the port is migrating"
+                      "nullable" = true
+                      "type" = "string"
+                    }
                     "password" = {
                       "type" = "string"
                     }
+                    "reconcileAt" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "secondary" = {
+                      "nullable" = true
+                      "properties" = {
+                        "password" = {
+                          "type" = "string"
+                        }
+                        "username" = {
+                          "type" = "string"
+                        }
+                      }
+                      "required" = [
+                        "password",
+                        "username",
+                      ]
+                      "type" = "object"
+                    }
+                    "stats" = {
+                      "nullable" = true
+                      "properties" = {
+                        "activeQueries" = {
+                          "format" = "int64"
+                          "type" = "integer"
+                        }
+                        "connections" = {
+                          "format" = "int64"
+                          "type" = "integer"
+                        }
+                        "longestQuerySeconds" = {
+                          "format" = "double"
+                          "type" = "number"
+                        }
+                        "recentQuerySeconds" = {
+                          "format" = "double"
+                          "type" = "number"
+                        }
+                        "sampledAt" = {
+                          "type" = "string"
+                        }
+                      }
+                      "required" = [
+                        "activeQueries",
+                        "connections",
+                        "longestQuerySeconds",
+                        "recentQuerySeconds",
+                        "sampledAt",
+                      ]
+                      "type" = "object"
+                    }
+                    "syncLagSeconds" = {
+                      "format" = "int64"
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "throughputTier" = {
+                      "description" = "Tier the port is metered at, it differs from the spec while a higher tier is over the quota"
+                      "format" = "uint32"
+                      "minimum" = 0.0
+                      "nullable" = true
+                      "type" = "integer"
+                    }
                     "username" = {
                       "type" = "string"
                     }
@@ -96,6 +510,132 @@ resource "kubernetes_manifest" "customresourcedefinition_dbsyncports_demeter_run
             }
           }
           "served" = true
+          "storage" = false
+          "subresources" = {
+            "status" = {}
+          }
+        },
+      ]
+    }
+  }
+}
+
+resource "kubernetes_manifest" "customresourcedefinition_internaldbusers_demeter_run" {
+  manifest = {
+    "apiVersion" = "apiextensions.k8s.io/v1"
+    "kind" = "CustomResourceDefinition"
+    "metadata" = {
+      "name" = "internaldbusers.demeter.run"
+    }
+    "spec" = {
+      "group" = "demeter.run"
+      "names" = {
+        "categories" = []
+        "kind" = "InternalDbUser"
+        "plural" = "internaldbusers"
+        "shortNames" = [
+          "idbu",
+        ]
+        "singular" = "internaldbuser"
+      }
+      "scope" = "Cluster"
+      "versions" = [
+        {
+          "additionalPrinterColumns" = [
+            {
+              "jsonPath" = ".status.username"
+              "name" = "Username"
+              "type" = "string"
+            },
+            {
+              "jsonPath" = ".spec.access"
+              "name" = "Access"
+              "type" = "string"
+            },
+            {
+              "jsonPath" = ".status.networks"
+              "name" = "Networks"
+              "type" = "string"
+            },
+          ]
+          "name" = "v1alpha1"
+          "schema" = {
+            "openAPIV3Schema" = {
+              "description" = "Auto-generated derived type for InternalDbUserSpec via `CustomResource`"
+              "properties" = {
+                "spec" = {
+                  "properties" = {
+                    "access" = {
+                      "default" = "read"
+                      "enum" = [
+                        "read",
+                        "write",
+                      ]
+                      "type" = "string"
+                    }
+                    "networks" = {
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "password" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "statementTimeout" = {
+                      "description" = "Statement timeout in milliseconds, defaults to STATEMENT_TIMEOUT"
+                      "format" = "uint64"
+                      "minimum" = 0.0
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "username" = {
+                      "type" = "string"
+                    }
+                  }
+                  "required" = [
+                    "networks",
+                    "username",
+                  ]
+                  "type" = "object"
+                }
+                "status" = {
+                  "nullable" = true
+                  "properties" = {
+                    "networks" = {
+                      "default" = []
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "password" = {
+                      "type" = "string"
+                    }
+                    "reconcileAt" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "username" = {
+                      "type" = "string"
+                    }
+                  }
+                  "required" = [
+                    "password",
+                    "username",
+                  ]
+                  "type" = "object"
+                }
+              }
+              "required" = [
+                "spec",
+              ]
+              "title" = "InternalDbUser"
+              "type" = "object"
+            }
+          }
+          "served" = true
           "storage" = true
           "subresources" = {
             "status" = {}
@@ -105,3 +645,112 @@ resource "kubernetes_manifest" "customresourcedefinition_dbsyncports_demeter_run
     }
   }
 }
+
+resource "kubernetes_manifest" "customresourcedefinition_dbsyncnetworks_demeter_run" {
+  manifest = {
+    "apiVersion" = "apiextensions.k8s.io/v1"
+    "kind" = "CustomResourceDefinition"
+    "metadata" = {
+      "name" = "dbsyncnetworks.demeter.run"
+    }
+    "spec" = {
+      "group" = "demeter.run"
+      "names" = {
+        "categories" = []
+        "kind" = "DbSyncNetwork"
+        "plural" = "dbsyncnetworks"
+        "shortNames" = [
+          "dbsn",
+        ]
+        "singular" = "dbsyncnetwork"
+      }
+      "scope" = "Cluster"
+      "versions" = [
+        {
+          "additionalPrinterColumns" = [
+            {
+              "jsonPath" = ".spec.databaseName"
+              "name" = "Database"
+              "type" = "string"
+            },
+            {
+              "jsonPath" = ".spec.aliases"
+              "name" = "Aliases"
+              "type" = "string"
+            },
+            {
+              "jsonPath" = ".spec.publicHost"
+              "name" = "Public Host"
+              "type" = "string"
+            },
+          ]
+          "name" = "v1alpha1"
+          "schema" = {
+            "openAPIV3Schema" = {
+              "description" = "Auto-generated derived type for DbSyncNetworkSpec via `CustomResource`"
+              "properties" = {
+                "spec" = {
+                  "properties" = {
+                    "aliases" = {
+                      "default" = []
+                      "description" = "Other names the ports can use for the network, the name of the object is the canonical one"
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "credentialsSecretRef" = {
+                      "description" = "Secret with the username and password the operator connects with"
+                      "properties" = {
+                        "name" = {
+                          "type" = "string"
+                        }
+                        "namespace" = {
+                          "type" = "string"
+                        }
+                      }
+                      "required" = [
+                        "name",
+                        "namespace",
+                      ]
+                      "type" = "object"
+                    }
+                    "databaseName" = {
+                      "type" = "string"
+                    }
+                    "endpoints" = {
+                      "description" = "Postgres instances serving the database, as host:port"
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "type" = "array"
+                    }
+                    "publicHost" = {
+                      "description" = "Host the users of the ports connect to"
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                  }
+                  "required" = [
+                    "credentialsSecretRef",
+                    "databaseName",
+                    "endpoints",
+                  ]
+                  "type" = "object"
+                }
+              }
+              "required" = [
+                "spec",
+              ]
+              "title" = "DbSyncNetwork"
+              "type" = "object"
+            }
+          }
+          "served" = true
+          "storage" = true
+          "subresources" = {}
+        },
+      ]
+    }
+  }
+}
@@ -6,10 +6,19 @@ variable "operator_image_tag" {
   type = string
 }
 
+variable "operator_replicas" {
+  default = 2
+}
+
 variable "metrics_delay" {
   default = 30
 }
 
+variable "internal_users" {
+  type    = string
+  default = "dmtr_blockfrost=blockfrost,dmtrro=postgrest"
+}
+
 
 variable "postgres_hosts" {
   type = list(string)
@@ -23,6 +32,10 @@ variable "postgres_password" {
   type = string
 }
 
+variable "pgbouncer_auth_user_password" {
+  type = string
+}
+
 variable "pgbouncer_server_crt" {
   type = string
 }
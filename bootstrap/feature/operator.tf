@@ -15,7 +15,7 @@ resource "kubernetes_deployment_v1" "operator" {
   }
 
   spec {
-    replicas = 1
+    replicas = var.operator_replicas
 
     selector {
       match_labels = {
@@ -31,6 +31,8 @@ resource "kubernetes_deployment_v1" "operator" {
       }
 
       spec {
+        termination_grace_period_seconds = 60
+
         container {
           image = "ghcr.io/demeter-run/ext-cardano-dbsync-serverless:${var.operator_image_tag}"
           name  = "main"
@@ -60,6 +62,11 @@ resource "kubernetes_deployment_v1" "operator" {
             }
           }
 
+          env {
+            name  = "PGBOUNCER_PASSWORD"
+            value = var.pgbouncer_auth_user_password
+          }
+
           env {
             name  = "DB_URLS"
             value = local.combined_postgres_urls
@@ -75,6 +82,40 @@ resource "kubernetes_deployment_v1" "operator" {
             value = "http://prometheus-operated.demeter-system.svc.cluster.local:9090/api/v1"
           }
 
+          env {
+            name  = "LEADER_ELECTION"
+            value = "true"
+          }
+
+          env {
+            name = "POD_NAME"
+            value_from {
+              field_ref {
+                field_path = "metadata.name"
+              }
+            }
+          }
+
+          env {
+            name  = "SHUTDOWN_GRACE_PERIOD"
+            value = "45"
+          }
+
+          env {
+            name  = "INTERNAL_USERS"
+            value = var.internal_users
+          }
+
+          env {
+            name  = "CONVERSION_TLS_CERT"
+            value = "/etc/webhook/tls.crt"
+          }
+
+          env {
+            name  = "CONVERSION_TLS_KEY"
+            value = "/etc/webhook/tls.key"
+          }
+
           env {
             name  = "RUST_BACKTRACE"
             value = "1"
@@ -96,6 +137,42 @@ resource "kubernetes_deployment_v1" "operator" {
             container_port = 5000
             protocol       = "TCP"
           }
+
+          port {
+            name           = "webhook"
+            container_port = 9443
+            protocol       = "TCP"
+          }
+
+          volume_mount {
+            name       = "webhook-tls"
+            mount_path = "/etc/webhook"
+            read_only  = true
+          }
+
+          liveness_probe {
+            http_get {
+              path = "/health"
+              port = 5000
+            }
+            initial_delay_seconds = 30
+            period_seconds        = 30
+          }
+
+          readiness_probe {
+            http_get {
+              path = "/ready"
+              port = 5000
+            }
+            period_seconds = 10
+          }
+        }
+
+        volume {
+          name = "webhook-tls"
+          secret {
+            secret_name = "operator-webhook-tls"
+          }
         }
 
         toleration {
@@ -8,6 +8,18 @@ resource "kubernetes_cluster_role" "cluster_role" {
     resources  = ["*"]
     verbs      = ["*"]
   }
+
+  rule {
+    api_groups = ["events.k8s.io"]
+    resources  = ["events"]
+    verbs      = ["create"]
+  }
+
+  rule {
+    api_groups = ["coordination.k8s.io"]
+    resources  = ["leases"]
+    verbs      = ["get", "create", "update"]
+  }
 }
 
 resource "kubernetes_cluster_role_binding" "cluster_role_binding" {
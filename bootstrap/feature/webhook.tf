@@ -0,0 +1,57 @@
+// The API server calls the conversion webhook of the DbSyncPort CRD over https. cert-manager
+// issues the certificate and injects its CA into the CRD (cert-manager.io/inject-ca-from).
+resource "kubernetes_manifest" "operator_webhook_issuer" {
+  manifest = {
+    apiVersion = "cert-manager.io/v1"
+    kind       = "Issuer"
+    metadata = {
+      name      = "operator-webhook"
+      namespace = var.namespace
+    }
+    spec = {
+      selfSigned = {}
+    }
+  }
+}
+
+resource "kubernetes_manifest" "operator_webhook_certificate" {
+  manifest = {
+    apiVersion = "cert-manager.io/v1"
+    kind       = "Certificate"
+    metadata = {
+      name      = "operator-webhook"
+      namespace = var.namespace
+    }
+    spec = {
+      secretName = "operator-webhook-tls"
+      dnsNames = [
+        "operator-webhook.${var.namespace}.svc",
+        "operator-webhook.${var.namespace}.svc.cluster.local",
+      ]
+      issuerRef = {
+        kind = "Issuer"
+        name = "operator-webhook"
+      }
+    }
+  }
+}
+
+resource "kubernetes_service_v1" "operator_webhook" {
+  metadata {
+    namespace = var.namespace
+    name      = "operator-webhook"
+  }
+
+  spec {
+    port {
+      name        = "webhook"
+      protocol    = "TCP"
+      port        = 9443
+      target_port = 9443
+    }
+
+    selector = {
+      role = "operator"
+    }
+  }
+}
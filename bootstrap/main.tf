@@ -17,6 +17,8 @@ module "dbsync_feature" {
   pgbouncer_server_crt = var.pgbouncer_server_crt
   pgbouncer_server_key = var.pgbouncer_server_key
 
+  pgbouncer_auth_user_password = var.pgbouncer_auth_user_password
+
   postgres_hosts = coalesce(var.postgres_hosts, [for key in keys(var.cells) : "postgres-dbsync-v3-${key}"])
 }
 
@@ -0,0 +1,545 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use dotenv::dotenv;
+use kube::{api::ListParams, Api, Client, ResourceExt};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+};
+
+use ext_cardano_dbsync::{
+    controller::DbSyncPort,
+    get_config,
+    internal_user::InternalDbUser,
+    networks::{DbSyncNetwork, Networks},
+    postgres::{Access, Grants, Member, Postgres, Provision},
+    Error,
+};
+
+#[derive(Parser)]
+#[command(about = "Inspect and repair the dbsync roles managed by the controller")]
+struct Cli {
+    /// Print the changes without applying them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    #[arg(long, global = true, value_enum, default_value_t = Output::Text)]
+    output: Output,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Output {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the login roles of each network and instance
+    Roles {
+        #[arg(long)]
+        network: Option<String>,
+    },
+    /// Compare the roles with the DbSyncPort objects
+    Diff {
+        #[arg(long)]
+        network: Option<String>,
+    },
+    /// Create the user of a port where it's missing and apply its grants again
+    Repair { namespace: String, name: String },
+    /// Drop the generated roles that don't belong to any DbSyncPort
+    DropOrphans {
+        #[arg(long)]
+        network: Option<String>,
+    },
+    /// Create a read-only account for an internal service, prefer an InternalDbUser
+    CreateInternal {
+        username: String,
+        #[arg(long, env = "INTERNAL_USER_PASSWORD")]
+        password: String,
+        #[arg(long)]
+        network: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Row {
+    network: String,
+    instance: String,
+    role: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<String>,
+    state: String,
+}
+
+struct Admin {
+    catalog: Networks,
+    pools: BTreeMap<String, Vec<Postgres>>,
+    dry_run: bool,
+}
+
+impl Admin {
+    fn networks(&self, network: &Option<String>) -> Result<Vec<(&String, &Vec<Postgres>)>, Error> {
+        match network {
+            Some(network) => {
+                let network = self.catalog.canonical(network);
+                let (network, connections) =
+                    self.pools
+                        .get_key_value(&network)
+                        .ok_or(Error::ConfigError(format!(
+                            "postgres not configured to {network}"
+                        )))?;
+                Ok(vec![(network, connections)])
+            }
+            None => Ok(self.pools.iter().collect()),
+        }
+    }
+
+    async fn roles(&self, network: &Option<String>) -> Result<Vec<Row>, Error> {
+        let mut rows = Vec::new();
+        for (network, connections) in self.networks(network)? {
+            for pg in connections {
+                for role in pg.list_roles(&get_config().username_prefix).await? {
+                    rows.push(Row {
+                        network: network.clone(),
+                        instance: pg.instance.clone(),
+                        role,
+                        port: None,
+                        state: "present".into(),
+                    });
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    async fn diff(
+        &self,
+        network: &Option<String>,
+        ports: &[DbSyncPort],
+        internal_users: &[InternalDbUser],
+    ) -> Result<Vec<Row>, Error> {
+        let config_users = &get_config().internal_users;
+        let internal_users: HashSet<&str> = internal_users
+            .iter()
+            .map(|user| user.spec.username.as_str())
+            .chain(config_users.keys().map(String::as_str))
+            .collect();
+
+        // Roles are shared by the databases of an instance, so a role is only an orphan when no
+        // port of any network uses it.
+        let known = known_roles(ports, &internal_users);
+
+        let mut rows = Vec::new();
+        for (network, connections) in self.networks(network)? {
+            let network_ports = port_roles(&self.catalog, ports, network);
+
+            for pg in connections {
+                let roles: HashSet<String> = pg
+                    .list_roles(&get_config().username_prefix)
+                    .await?
+                    .into_iter()
+                    .collect();
+
+                for (username, port) in network_ports.iter() {
+                    rows.push(Row {
+                        network: network.clone(),
+                        instance: pg.instance.clone(),
+                        role: username.to_string(),
+                        port: Some(port.clone()),
+                        state: role_state(&roles, username).into(),
+                    });
+                }
+
+                for role in orphans(&roles, &known, &get_config().username_prefix) {
+                    rows.push(Row {
+                        network: network.clone(),
+                        instance: pg.instance.clone(),
+                        role: role.clone(),
+                        port: None,
+                        state: "orphan".into(),
+                    });
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    async fn repair(&self, port: &DbSyncPort) -> Result<Vec<Row>, Error> {
+        let status = port
+            .status
+            .as_ref()
+            .ok_or(Error::ConfigError("port doesn't have a status yet".into()))?;
+        let name = format!("{}/{}", port.namespace().unwrap(), port.name_any());
+
+        let group = port.group(status);
+        let grants = port.grants();
+
+        let network = Some(port.spec.network.to_string());
+        let mut rows = Vec::new();
+        for (network, connections) in self.networks(&network)? {
+            for pg in connections {
+                for member in port.members(status) {
+                    let state = self.provision_member(pg, &group, &member, &grants).await?;
+                    rows.push(Row {
+                        network: network.clone(),
+                        instance: pg.instance.clone(),
+                        role: member.username,
+                        port: Some(name.clone()),
+                        state,
+                    });
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    async fn drop_orphans(
+        &self,
+        network: &Option<String>,
+        ports: &[DbSyncPort],
+        internal_users: &[InternalDbUser],
+    ) -> Result<Vec<Row>, Error> {
+        let orphans: Vec<Row> = self
+            .diff(network, ports, internal_users)
+            .await?
+            .into_iter()
+            .filter(|row| row.state == "orphan")
+            .collect();
+
+        // Owned objects are dropped on every database of the instance before the role itself
+        let mut instances: HashMap<&str, Vec<&Postgres>> = HashMap::new();
+        for pg in self.pools.values().flatten() {
+            instances.entry(&pg.instance).or_default().push(pg);
+        }
+
+        let mut rows = Vec::new();
+        let mut dropped: HashSet<(String, String)> = HashSet::new();
+        for orphan in orphans {
+            let key = (orphan.instance.clone(), orphan.role.clone());
+            if !dropped.insert(key) {
+                continue;
+            }
+
+            let state = if self.dry_run {
+                "would drop".to_string()
+            } else {
+                let connections = &instances[orphan.instance.as_str()];
+                match drop_role(connections, &orphan.role).await {
+                    Ok(()) => "dropped".into(),
+                    Err(err) => format!("failed: {err}"),
+                }
+            };
+
+            rows.push(Row { state, ..orphan });
+        }
+        Ok(rows)
+    }
+
+    async fn create_internal(
+        &self,
+        username: &str,
+        password: &str,
+        network: &Option<String>,
+    ) -> Result<Vec<Row>, Error> {
+        let grants = Grants {
+            access: Access::Read,
+            statement_timeout: get_config().statement_timeout,
+        };
+
+        let mut rows = Vec::new();
+        for (network, connections) in self.networks(network)? {
+            for pg in connections {
+                let state = self.provision(pg, username, password, &grants).await?;
+                rows.push(Row {
+                    network: network.clone(),
+                    instance: pg.instance.clone(),
+                    role: username.into(),
+                    port: None,
+                    state,
+                });
+            }
+        }
+        Ok(rows)
+    }
+
+    async fn provision(
+        &self,
+        pg: &Postgres,
+        username: &str,
+        password: &str,
+        grants: &Grants,
+    ) -> Result<String, Error> {
+        if self.dry_run {
+            let state = match pg.user_exist(username).await? {
+                true => "would grant",
+                false => "would create",
+            };
+            return Ok(state.into());
+        }
+
+        let state = match pg.create_user(username, password, grants, true).await {
+            Ok(Provision::Created) => "created".into(),
+            Ok(Provision::DriftFixed) | Ok(Provision::Unchanged) => "granted".into(),
+            Err(err) => format!("failed: {err}"),
+        };
+        Ok(state)
+    }
+
+    async fn provision_member(
+        &self,
+        pg: &Postgres,
+        group: &str,
+        member: &Member,
+        grants: &Grants,
+    ) -> Result<String, Error> {
+        if self.dry_run {
+            let state = match pg.user_exist(&member.username).await? {
+                true => "would grant",
+                false => "would create",
+            };
+            return Ok(state.into());
+        }
+
+        let created = match pg.create_group(group, grants, true).await {
+            Ok(_) => {
+                pg.create_member(member, group, grants.statement_timeout, true)
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        let state = match created {
+            Ok(Provision::Created) => "created".into(),
+            Ok(Provision::DriftFixed) | Ok(Provision::Unchanged) => "granted".into(),
+            Err(err) => format!("failed: {err}"),
+        };
+        Ok(state)
+    }
+}
+
+// The roles used by any port, including its group, or internal user
+fn known_roles(ports: &[DbSyncPort], internal_users: &HashSet<&str>) -> HashSet<String> {
+    ports
+        .iter()
+        .filter_map(|port| Some((port, port.status.as_ref()?)))
+        .flat_map(|(port, status)| {
+            let usernames = status.usernames().into_iter().map(String::from);
+            usernames.chain([port.group(status)])
+        })
+        .chain(internal_users.iter().map(|user| user.to_string()))
+        .collect()
+}
+
+// The roles of the ports of a network with the port they belong to
+fn port_roles<'a>(
+    catalog: &Networks,
+    ports: &'a [DbSyncPort],
+    network: &str,
+) -> Vec<(&'a str, String)> {
+    ports
+        .iter()
+        .filter(|port| catalog.canonical(port.spec.network.as_str()) == network)
+        .filter_map(|port| {
+            let status = port.status.as_ref()?;
+            let name = format!("{}/{}", port.namespace().unwrap(), port.name_any());
+            Some(
+                status
+                    .usernames()
+                    .into_iter()
+                    .map(move |u| (u, name.clone())),
+            )
+        })
+        .flatten()
+        .collect()
+}
+
+fn role_state(roles: &HashSet<String>, username: &str) -> &'static str {
+    match roles.contains(username) {
+        true => "ok",
+        false => "missing",
+    }
+}
+
+// The generated roles of an instance that aren't known, sorted
+fn orphans<'a>(
+    roles: &'a HashSet<String>,
+    known: &HashSet<String>,
+    prefix: &str,
+) -> Vec<&'a String> {
+    let mut orphans: Vec<&String> = roles
+        .iter()
+        .filter(|role| role.starts_with(prefix))
+        .filter(|role| !known.contains(*role))
+        .collect();
+    orphans.sort();
+    orphans
+}
+
+async fn drop_role(connections: &[&Postgres], username: &str) -> Result<(), Error> {
+    for pg in connections {
+        pg.drop_owned(username).await?;
+    }
+    connections[0].drop_user(username).await
+}
+
+// The networks of DB_NAMES and of the DbSyncNetwork objects, like the controller serves them
+async fn connect_networks() -> Result<Networks, Error> {
+    let config = get_config();
+    let catalog = Networks::from_config(config).await?;
+
+    let client = Client::try_default().await?;
+    let api = Api::<DbSyncNetwork>::all(client.clone());
+    let networks = api.list(&ListParams::default()).await?.items;
+    let failures = catalog
+        .update(&client, &config.db_max_connections, &networks)
+        .await;
+    for (network, err) in failures {
+        eprintln!("network {} skipped: {err}", network.name_any());
+    }
+
+    Ok(catalog)
+}
+
+async fn list_ports() -> Result<Vec<DbSyncPort>, Error> {
+    let client = Client::try_default().await?;
+    let api = Api::<DbSyncPort>::all(client);
+    Ok(api.list(&ListParams::default()).await?.items)
+}
+
+async fn list_internal_users() -> Result<Vec<InternalDbUser>, Error> {
+    let client = Client::try_default().await?;
+    let api = Api::<InternalDbUser>::all(client);
+    Ok(api.list(&ListParams::default()).await?.items)
+}
+
+fn print(rows: &[Row], output: Output) -> io::Result<()> {
+    match output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(rows)?),
+        Output::Text => {
+            for row in rows {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    row.network,
+                    row.instance,
+                    row.role,
+                    row.port.as_deref().unwrap_or("-"),
+                    row.state
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    dotenv().ok();
+
+    let cli = Cli::parse();
+
+    let catalog = connect_networks().await?;
+    let admin = Admin {
+        pools: catalog.snapshot(),
+        catalog,
+        dry_run: cli.dry_run,
+    };
+
+    let rows = match &cli.command {
+        Command::Roles { network } => admin.roles(network).await?,
+        Command::Diff { network } => {
+            admin
+                .diff(network, &list_ports().await?, &list_internal_users().await?)
+                .await?
+        }
+        Command::Repair { namespace, name } => {
+            let client = Client::try_default().await.map_err(Error::from)?;
+            let api = Api::<DbSyncPort>::namespaced(client, namespace);
+            let port = api.get(name).await.map_err(Error::from)?;
+            admin.repair(&port).await?
+        }
+        Command::DropOrphans { network } => {
+            admin
+                .drop_orphans(network, &list_ports().await?, &list_internal_users().await?)
+                .await?
+        }
+        Command::CreateInternal {
+            username,
+            password,
+            network,
+        } => admin.create_internal(username, password, network).await?,
+    };
+
+    print(&rows, cli.output)
+}
+
+#[cfg(test)]
+#[path = "fixtures.rs"]
+mod fixtures;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(name: &str, network: &str, usernames: &[&str]) -> DbSyncPort {
+        fixtures::provisioned(fixtures::port(name, network), usernames)
+    }
+
+    fn role_set(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_port_roles() {
+        let ports = vec![
+            port("a", "cardano-mainnet", &["dmtr_dbsync_a", "dmtr_dbsync_a2"]),
+            port("b", "cardano-preprod", &["dmtr_dbsync_b"]),
+        ];
+
+        let port_roles = port_roles(&Networks::default(), &ports, "cardano-mainnet");
+        assert_eq!(
+            port_roles,
+            vec![
+                ("dmtr_dbsync_a", "prj-test/a".to_string()),
+                ("dmtr_dbsync_a2", "prj-test/a".to_string()),
+            ]
+        );
+
+        let roles = role_set(&["dmtr_dbsync_a"]);
+        assert_eq!(role_state(&roles, "dmtr_dbsync_a"), "ok");
+        assert_eq!(role_state(&roles, "dmtr_dbsync_a2"), "missing");
+    }
+
+    #[test]
+    fn test_orphans() {
+        // A role of a port on another network isn't an orphan, the instance is shared
+        let ports = vec![
+            port("a", "cardano-mainnet", &["dmtr_dbsync_a"]),
+            port("b", "cardano-preprod", &["dmtr_dbsync_b"]),
+        ];
+        let internal_users = HashSet::from(["dmtr_dbsync_internal"]);
+        let known = known_roles(&ports, &internal_users);
+
+        let roles = role_set(&[
+            "dmtr_dbsync_z",
+            "dmtr_dbsync_a",
+            "dmtr_dbsync_b",
+            "dmtr_dbsync_internal",
+            "dmtr_dbsync_c",
+            "postgres",
+        ]);
+        assert_eq!(
+            orphans(&roles, &known, "dmtr_dbsync"),
+            vec!["dmtr_dbsync_c", "dmtr_dbsync_z"]
+        );
+
+        // The group holds the grants of the port's credentials
+        let roles = role_set(&["dmtr_dbsync_a_group", "dmtr_dbsync_c_group"]);
+        assert_eq!(
+            orphans(&roles, &known, "dmtr_dbsync"),
+            vec!["dmtr_dbsync_c_group"]
+        );
+    }
+}
@@ -1,6 +1,12 @@
 use lazy_static::lazy_static;
 use std::{collections::HashMap, env, time::Duration};
 
+use crate::{
+    project::{self, ProjectSource},
+    quota::{self, Quota},
+    username::UsernameGenerator,
+};
+
 lazy_static! {
     static ref CONTROLLER_CONFIG: Config = Config::from_env();
 }
@@ -9,6 +15,12 @@ pub fn get_config() -> &'static Config {
     &CONTROLLER_CONFIG
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub db_urls: Vec<String>,
@@ -18,19 +30,58 @@ pub struct Config {
     pub metrics_delay: Duration,
     pub prometheus_url: String,
     pub statement_timeout: u64,
+    pub loop_stall_threshold: Duration,
+    pub internal_users: HashMap<String, String>,
+    pub port_stats_interval: Duration,
+
+    pub leader_election: bool,
+    pub lease_name: String,
+    pub lease_namespace: Option<String>,
+    pub lease_identity: String,
+    pub lease_duration: Duration,
+
+    pub log_format: LogFormat,
+    pub otlp_endpoint: Option<String>,
+
+    pub shutdown_grace_period: Duration,
+
+    pub resync_interval: Duration,
+    pub resync_jitter: f64,
+
+    pub schema_check_interval: Duration,
+    pub schema_apply: bool,
+    pub pgbouncer_password: Option<String>,
+
+    pub sync_lag_threshold: Duration,
+    pub instance_check_interval: Duration,
+    pub instance_failure_threshold: u32,
+
+    pub conversion_tls_cert: Option<String>,
+    pub conversion_tls_key: Option<String>,
+
+    pub username_prefix: String,
+    pub username_length: usize,
+
+    pub project_sources: Vec<ProjectSource>,
+
+    pub quotas: HashMap<String, Quota>,
+    pub quota_label: String,
 }
 
 impl Config {
     pub fn from_env() -> Self {
+        // Networks can be described by DbSyncNetwork objects only, so these are optional
         let db_urls = env::var("DB_URLS")
-            .expect("DB_URLS must be set")
+            .unwrap_or_default()
             .split(',')
+            .filter(|url| !url.is_empty())
             .map(|s| s.into())
             .collect();
 
         let db_names = env::var("DB_NAMES")
-            .expect("DB_NAMES must be set")
+            .unwrap_or_default()
             .split(',')
+            .filter(|pair| !pair.is_empty())
             .map(|pair| {
                 let parts: Vec<&str> = pair.split('=').collect();
                 (parts[0].into(), parts[1].into())
@@ -58,6 +109,148 @@ impl Config {
             .parse::<u64>()
             .expect("STATEMENT_TIMEOUT must be a number");
 
+        let loop_stall_threshold = Duration::from_secs(
+            env::var("LOOP_STALL_THRESHOLD")
+                .unwrap_or("300".to_string())
+                .parse::<u64>()
+                .expect("LOOP_STALL_THRESHOLD must be a number"),
+        );
+        // The collector only ticks every METRICS_DELAY, it would always look stalled otherwise
+        if loop_stall_threshold <= metrics_delay {
+            panic!("LOOP_STALL_THRESHOLD must be greater than METRICS_DELAY");
+        }
+
+        let internal_users = env::var("INTERNAL_USERS")
+            .map(|v| {
+                v.split(',')
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| {
+                        let (username, project) = pair
+                            .split_once('=')
+                            .expect("INTERNAL_USERS must be username=project pairs");
+                        (username.into(), project.into())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let port_stats_interval = Duration::from_secs(
+            env::var("PORT_STATS_INTERVAL")
+                .unwrap_or("300".to_string())
+                .parse::<u64>()
+                .expect("PORT_STATS_INTERVAL must be a number"),
+        );
+
+        let leader_election = env::var("LEADER_ELECTION")
+            .map(|v| {
+                v.parse::<bool>()
+                    .expect("LEADER_ELECTION must be true or false")
+            })
+            .unwrap_or(false);
+
+        let lease_name =
+            env::var("LEASE_NAME").unwrap_or("ext-cardano-dbsync-operator".to_string());
+
+        let lease_namespace = env::var("LEASE_NAMESPACE").ok();
+
+        let lease_identity = env::var("POD_NAME")
+            .or_else(|_| env::var("HOSTNAME"))
+            .unwrap_or("ext-cardano-dbsync-operator".to_string());
+
+        let lease_duration = Duration::from_secs(
+            env::var("LEASE_DURATION")
+                .unwrap_or("15".to_string())
+                .parse::<u64>()
+                .expect("LEASE_DURATION must be a number"),
+        );
+
+        let log_format = match env::var("LOG_FORMAT").as_deref() {
+            Ok("json") => LogFormat::Json,
+            Ok("text") | Err(_) => LogFormat::Text,
+            Ok(_) => panic!("LOG_FORMAT must be json or text"),
+        };
+
+        let otlp_endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .ok()
+            .map(|v| v.trim_end_matches('/').to_string());
+
+        let shutdown_grace_period = Duration::from_secs(
+            env::var("SHUTDOWN_GRACE_PERIOD")
+                .unwrap_or("30".to_string())
+                .parse::<u64>()
+                .expect("SHUTDOWN_GRACE_PERIOD must be a number"),
+        );
+
+        let resync_interval = Duration::from_secs(
+            env::var("RESYNC_INTERVAL")
+                .unwrap_or("3600".to_string())
+                .parse::<u64>()
+                .expect("RESYNC_INTERVAL must be a number"),
+        );
+
+        let resync_jitter = env::var("RESYNC_JITTER")
+            .unwrap_or("0.1".to_string())
+            .parse::<f64>()
+            .ok()
+            .filter(|v| (0. ..1.).contains(v))
+            .expect("RESYNC_JITTER must be a number between 0 and 1");
+
+        let schema_check_interval = Duration::from_secs(
+            env::var("SCHEMA_CHECK_INTERVAL")
+                .unwrap_or("600".to_string())
+                .parse::<u64>()
+                .expect("SCHEMA_CHECK_INTERVAL must be a number"),
+        );
+
+        let schema_apply = env::var("SCHEMA_APPLY")
+            .map(|v| {
+                v.parse::<bool>()
+                    .expect("SCHEMA_APPLY must be true or false")
+            })
+            .unwrap_or(true);
+
+        let pgbouncer_password = env::var("PGBOUNCER_PASSWORD").ok();
+
+        let sync_lag_threshold = Duration::from_secs(
+            env::var("SYNC_LAG_THRESHOLD")
+                .unwrap_or("600".to_string())
+                .parse::<u64>()
+                .expect("SYNC_LAG_THRESHOLD must be a number"),
+        );
+
+        let instance_check_interval = Duration::from_secs(
+            env::var("INSTANCE_CHECK_INTERVAL")
+                .unwrap_or("30".to_string())
+                .parse::<u64>()
+                .expect("INSTANCE_CHECK_INTERVAL must be a number"),
+        );
+
+        let instance_failure_threshold = env::var("INSTANCE_FAILURE_THRESHOLD")
+            .unwrap_or("3".to_string())
+            .parse::<u32>()
+            .expect("INSTANCE_FAILURE_THRESHOLD must be a number");
+
+        let conversion_tls_cert = env::var("CONVERSION_TLS_CERT").ok();
+        let conversion_tls_key = env::var("CONVERSION_TLS_KEY").ok();
+
+        let username_prefix = env::var("USERNAME_PREFIX").unwrap_or("dmtr_dbsync".to_string());
+        let username_length = env::var("USERNAME_LENGTH")
+            .unwrap_or("32".to_string())
+            .parse::<usize>()
+            .expect("USERNAME_LENGTH must be a number");
+        if let Err(err) = UsernameGenerator::new(&username_prefix, username_length) {
+            panic!("invalid USERNAME_PREFIX or USERNAME_LENGTH: {err}");
+        }
+
+        let project_sources = project::parse_sources(
+            &env::var("PROJECT_SOURCES").unwrap_or("prefix=prj-,spec".to_string()),
+        )
+        .expect("PROJECT_SOURCES must be a list of spec, label=, annotation= or prefix=");
+
+        let quotas = quota::parse_quotas(&env::var("PORT_QUOTAS").unwrap_or_default())
+            .expect("PORT_QUOTAS must be a list of name=ports:tierUnits");
+        let quota_label = env::var("QUOTA_LABEL").unwrap_or("demeter.run/port-quota".to_string());
+
         Self {
             db_urls,
             db_names,
@@ -65,6 +258,32 @@ impl Config {
             metrics_delay,
             prometheus_url,
             statement_timeout,
+            loop_stall_threshold,
+            internal_users,
+            port_stats_interval,
+            leader_election,
+            lease_name,
+            lease_namespace,
+            lease_identity,
+            lease_duration,
+            log_format,
+            otlp_endpoint,
+            shutdown_grace_period,
+            resync_interval,
+            resync_jitter,
+            schema_check_interval,
+            schema_apply,
+            pgbouncer_password,
+            sync_lag_threshold,
+            instance_check_interval,
+            instance_failure_threshold,
+            conversion_tls_cert,
+            conversion_tls_key,
+            username_prefix,
+            username_length,
+            project_sources,
+            quotas,
+            quota_label,
         }
     }
 }
@@ -83,6 +302,11 @@ mod tests {
         env::set_var("METRICS_DELAY", "100");
         env::set_var("PROMETHEUS_URL", "localhost");
         env::set_var("STATEMENT_TIMEOUT", "100");
+        env::set_var("LOG_FORMAT", "json");
+        env::set_var(
+            "INTERNAL_USERS",
+            "dmtr_blockfrost=blockfrost,dmtrro=postgrest",
+        );
 
         let config = Config::from_env();
         assert_eq!(config.db_urls, vec!["url1".to_owned(), "url2".to_owned()]);
@@ -95,10 +319,49 @@ mod tests {
             ])
         );
         assert_eq!(config.statement_timeout, 100);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(
+            config.internal_users,
+            HashMap::from([
+                ("dmtr_blockfrost".to_owned(), "blockfrost".to_owned()),
+                ("dmtrro".to_owned(), "postgrest".to_owned())
+            ])
+        );
 
         // Check default query timeout
         env::remove_var("STATEMENT_TIMEOUT");
+        env::remove_var("INTERNAL_USERS");
+        env::remove_var("LOG_FORMAT");
+        env::remove_var("DB_URLS");
+        env::remove_var("DB_NAMES");
         let config = Config::from_env();
         assert_eq!(config.statement_timeout, 120000);
+        assert_eq!(config.loop_stall_threshold, Duration::from_secs(300));
+        assert!(config.internal_users.is_empty());
+        assert_eq!(config.port_stats_interval, Duration::from_secs(300));
+        assert!(!config.leader_election);
+        assert_eq!(config.lease_duration, Duration::from_secs(15));
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert_eq!(config.shutdown_grace_period, Duration::from_secs(30));
+        assert_eq!(config.resync_interval, Duration::from_secs(3600));
+        assert_eq!(config.resync_jitter, 0.1);
+        assert_eq!(config.schema_check_interval, Duration::from_secs(600));
+        assert!(config.schema_apply);
+        assert!(config.pgbouncer_password.is_none());
+        assert_eq!(config.sync_lag_threshold, Duration::from_secs(600));
+        assert_eq!(config.instance_check_interval, Duration::from_secs(30));
+        assert_eq!(config.instance_failure_threshold, 3);
+        assert!(config.conversion_tls_cert.is_none());
+        assert!(config.conversion_tls_key.is_none());
+        assert_eq!(config.username_prefix, "dmtr_dbsync");
+        assert_eq!(config.username_length, 32);
+        assert_eq!(
+            config.project_sources,
+            vec![ProjectSource::Prefix("prj-".into()), ProjectSource::Spec]
+        );
+        assert!(config.quotas.is_empty());
+        assert_eq!(config.quota_label, "demeter.run/port-quota");
+        assert!(config.db_urls.is_empty());
+        assert!(config.db_names.is_empty());
     }
 }
@@ -1,74 +1,418 @@
-use bech32::ToBase32;
-use futures::{future, StreamExt};
+use chrono::{DateTime, Utc};
+use futures::{future, Future, StreamExt};
 use kube::{
     api::{ListParams, Patch, PatchParams},
     runtime::{
         controller::Action,
+        events::EventType,
         finalizer::{finalizer, Event},
+        reflector, watcher,
         watcher::Config as WatcherConfig,
-        Controller,
+        Controller, WatchStreamExt,
     },
-    Api, Client, CustomResource, ResourceExt,
+    Api, Client, CustomResource, Resource, ResourceExt,
 };
-use rand::distributions::{Alphanumeric, DistString};
-use schemars::JsonSchema;
+use rand::Rng;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use sha3::{Digest, Sha3_256};
-use std::{sync::Arc, time::Duration};
-use tracing::{error, info, instrument};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, instrument, warn};
 
-use crate::{postgres::Postgres, utils::handle_legacy_networks, Error, State};
+use crate::{
+    credentials::{desired_credentials, generate_password},
+    events, get_config,
+    health::CONTROLLER_LOOP,
+    internal_user::{self, InternalDbUser},
+    postgres::{Access, Grants, Postgres, Provision},
+    quota::{self, Admission},
+    username::UsernameGenerator,
+    utils::handle_legacy_networks,
+    Error, State,
+};
 
 pub static DB_SYNC_PORT_FINALIZER: &str = "dbsyncports.demeter.run";
+pub static RECONCILE_AT_ANNOTATION: &str = "demeter.run/reconcile-at";
+pub const READY_CONDITION: &str = "Ready";
+pub const MIGRATING_CONDITION: &str = "Migrating";
+pub const QUOTA_CONDITION: &str = "QuotaExceeded";
+// The conditions the reconciler owns, the collectors own the others
+const RECONCILER_CONDITIONS: [&str; 3] = [READY_CONDITION, MIGRATING_CONDITION, QUOTA_CONDITION];
+const STATUS_PATCH_ATTEMPTS: usize = 3;
+pub const MAX_THROUGHPUT_TIER: u32 = 3;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+// Postgres truncates identifiers longer than 63 bytes, the pattern keeps them single-byte
+const USERNAME_MAX_LENGTH: usize = 63;
+pub(crate) const USERNAME_PATTERN: &str = "^[a-z_][a-z0-9_]*$";
+// Networks are named like the Kubernetes objects that describe them
+const NETWORK_MAX_LENGTH: usize = 63;
+const NETWORK_PATTERN: &str = "^[a-z0-9]([-a-z0-9]*[a-z0-9])?$";
 
-#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
 #[kube(
     kind = "DbSyncPort",
     group = "demeter.run",
-    version = "v1alpha1",
+    version = "v1beta1",
     category = "demeter-port",
     shortname = "dbsp",
     namespaced
 )]
-#[kube(status = "DbSyncPortStatus")]
+#[kube(status = "DbSyncPortStatus", derive = "PartialEq")]
 #[kube(printcolumn = r#"
         {"name": "Network", "jsonPath": ".spec.network", "type": "string"},
-        {"name": "Throughput Tier", "jsonPath":".spec.throughputTier", "type": "string"}, 
+        {"name": "Throughput Tier", "jsonPath":".spec.throughputTier", "type": "integer"},
         {"name": "Username", "jsonPath": ".status.username",  "type": "string"},
-        {"name": "Password", "jsonPath": ".status.password", "type": "string"}
+        {"name": "Password", "jsonPath": ".status.password", "type": "string"},
+        {"name": "Synced", "jsonPath": ".status.conditions[?(@.type==\"ChainSynced\")].status", "type": "string"}
     "#)]
 #[serde(rename_all = "camelCase")]
 pub struct DbSyncPortSpec {
-    pub network: String,
-    pub throughput_tier: Option<String>,
+    #[schemars(schema_with = "network_schema")]
+    pub network: Network,
+    #[schemars(range(max = "MAX_THROUGHPUT_TIER"))]
+    pub throughput_tier: Option<u32>,
+    #[serde(default)]
+    #[schemars(schema_with = "username_schema")]
     pub username: Option<String>,
-    pub password: Option<String>,
+    /// Secret in the namespace of the port with the `password` and optionally the `username`
+    pub credentials_secret_ref: Option<CredentialsSecretRef>,
+    #[serde(default)]
+    pub access_profile: AccessProfile,
+    /// Expiry of the primary credential, the role can't log in afterwards
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub expires_at: Option<String>,
+    /// A second credential valid at the same time as the primary one, for rollovers
+    pub secondary_credential: Option<SecondaryCredential>,
+    /// Lifetime of the primary password in hours or days, e.g. `720h` or `30d`. The password is
+    /// renewed when a third of it is left.
+    #[schemars(regex(pattern = r"^[1-9][0-9]*(h|d)$"))]
+    pub credential_ttl: Option<String>,
+    /// Project the usage of the port is reported under when its namespace doesn't belong to one
+    pub project_ref: Option<String>,
+}
+/// Canonical name or alias of a DbSyncNetwork, or a network of DB_NAMES
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct Network(String);
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct CredentialsSecretRef {
+    pub name: String,
+}
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SecondaryCredential {
+    /// Secret with the `password` and optionally the `username`, generated when not set
+    pub credentials_secret_ref: Option<CredentialsSecretRef>,
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub expires_at: Option<String>,
+}
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum AccessProfile {
+    #[default]
+    Standard,
+    ReadOnly,
+}
+
+pub(crate) fn username_schema(_: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(json!({
+        "type": "string",
+        "nullable": true,
+        "minLength": 1,
+        "maxLength": USERNAME_MAX_LENGTH,
+        "pattern": USERNAME_PATTERN,
+    }))
+    .unwrap()
+}
+
+// The rules of the schema, for the usernames that don't go through it like the ones of a Secret
+pub(crate) fn is_valid_username(username: &str) -> bool {
+    let mut chars = username.chars();
+    let valid_first = |c: char| c.is_ascii_lowercase() || c == '_';
+    username.len() <= USERNAME_MAX_LENGTH
+        && chars.next().is_some_and(valid_first)
+        && chars.all(|c| valid_first(c) || c.is_ascii_digit())
+}
+
+// The roles of postgres itself, no port or internal user can take one of them over
+pub(crate) fn is_system_role(username: &str) -> bool {
+    username == "postgres" || username.starts_with("pg_")
+}
+
+// A superuser on any of the instances was never created by the controller
+pub(crate) async fn is_superuser(
+    username: &str,
+    pg_connections: &[Postgres],
+) -> Result<bool, Error> {
+    let superuser =
+        future::try_join_all(pg_connections.iter().map(|pg| pg.is_superuser(username))).await?;
+    Ok(superuser.into_iter().any(|superuser| superuser))
 }
-#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+
+pub(crate) fn network_schema(_: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(json!({
+        "type": "string",
+        "minLength": 1,
+        "maxLength": NETWORK_MAX_LENGTH,
+        "pattern": NETWORK_PATTERN,
+    }))
+    .unwrap()
+}
+
+impl Network {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// The legacy names of the cardano networks are replaced by the canonical ones, other names are
+// resolved against the network catalogue when the port is reconciled.
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let valid = !value.is_empty()
+            && value.len() <= NETWORK_MAX_LENGTH
+            && value
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            && !value.starts_with('-')
+            && !value.ends_with('-');
+        if !valid {
+            return Err(Error::ConfigError(format!("invalid network {value}")));
+        }
+        Ok(Network(handle_legacy_networks(value)))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct DbSyncPortStatus {
     pub username: String,
     pub password: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<DbSyncPortStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconcile_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<DbSyncPortCondition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync_lag_seconds: Option<i64>,
+    /// Network the user is provisioned on, it differs from the spec while the port is migrating
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<String>,
+    /// Role holding the grants, the credentials of the port are its members
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secondary: Option<SecondaryCredentialStatus>,
+    /// When the primary password expires, only set with a credentialTtl
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials_expire_at: Option<String>,
+    /// Host to connect to, the public host of the DbSyncNetwork
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Tier the port is metered at, it differs from the spec while a higher tier is over the
+    /// quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throughput_tier: Option<u32>,
+}
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SecondaryCredentialStatus {
+    pub username: String,
+    pub password: String,
+}
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DbSyncPortStats {
+    pub connections: i64,
+    pub active_queries: i64,
+    pub longest_query_seconds: f64,
+    pub recent_query_seconds: f64,
+    pub sampled_at: String,
+}
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DbSyncPortCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    pub reason: String,
+    #[serde(default)]
+    pub message: String,
+    pub last_transition_time: String,
+}
+
+// Sets the condition and returns whether the conditions changed. The transition time is only
+// updated when the status flips, as in the Kubernetes conditions.
+pub fn set_condition(
+    conditions: &mut Vec<DbSyncPortCondition>,
+    type_: &str,
+    status: bool,
+    reason: &str,
+    message: String,
+) -> bool {
+    let status = if status { "True" } else { "False" };
+
+    let Some(condition) = conditions.iter_mut().find(|c| c.type_ == type_) else {
+        conditions.push(DbSyncPortCondition {
+            type_: type_.into(),
+            status: status.into(),
+            reason: reason.into(),
+            message,
+            last_transition_time: Utc::now().to_rfc3339(),
+        });
+        return true;
+    };
+
+    if condition.status == status && condition.reason == reason && condition.message == message {
+        return false;
+    }
+    if condition.status != status {
+        condition.last_transition_time = Utc::now().to_rfc3339();
+    }
+    condition.status = status.into();
+    condition.reason = reason.into();
+    condition.message = message;
+    true
 }
+
+// Copies the conditions of the given types, returns whether any changed
+pub fn copy_conditions(
+    from: &[DbSyncPortCondition],
+    to: &mut Vec<DbSyncPortCondition>,
+    types: &[&str],
+) -> bool {
+    let mut changed = false;
+    for condition in from.iter().filter(|c| types.contains(&c.type_.as_str())) {
+        match to.iter_mut().find(|c| c.type_ == condition.type_) {
+            Some(existing) if existing == condition => {}
+            Some(existing) => {
+                *existing = condition.clone();
+                changed = true;
+            }
+            None => {
+                to.push(condition.clone());
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// The reconciler and the collectors write the conditions, and a merge patch replaces the whole
+// list. The status is read again and patched with its resourceVersion, so each writer only
+// changes what it owns and tries again when another one was faster. Returns whether it changed.
+pub async fn update_status<F>(api: &Api<DbSyncPort>, name: &str, update: F) -> Result<bool, Error>
+where
+    F: Fn(&mut DbSyncPortStatus) -> bool,
+{
+    let mut attempt = 1;
+    loop {
+        let port = api.get_status(name).await?;
+        let Some(mut status) = port.status.clone() else {
+            return Ok(false);
+        };
+        if !update(&mut status) {
+            return Ok(false);
+        }
+
+        // Fields cleared by the update are sent as null, the merge patch removes them
+        let mut fields = json!(status);
+        if let (Some(previous), Some(fields)) =
+            (json!(port.status).as_object(), fields.as_object_mut())
+        {
+            for key in previous.keys() {
+                fields.entry(key.clone()).or_insert(serde_json::Value::Null);
+            }
+        }
+        let payload = json!({
+            "metadata": { "resourceVersion": port.resource_version() },
+            "status": fields
+        });
+        match api
+            .patch_status(name, &PatchParams::default(), &Patch::Merge(payload))
+            .await
+        {
+            Ok(_) => return Ok(true),
+            Err(kube::Error::Api(err)) if err.code == 409 && attempt < STATUS_PATCH_ATTEMPTS => {
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
 impl DbSyncPortStatus {
-    pub async fn try_new(port: &DbSyncPort) -> Result<Self, Error> {
+    pub async fn try_new(port: &DbSyncPort, state: &State) -> Result<Self, Error> {
         let ns = port.namespace().unwrap();
         let name = port.name_any();
 
-        let username = match &port.spec.username {
-            Some(username) => username.clone(),
-            None => gen_username_hash(&format!("{name}.{ns}")).await?,
-        };
-        let password = match &port.spec.password {
-            Some(password) => password.clone(),
-            None => Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+        // A port recreated with the same name gets the same role, unless another port took it
+        let (username, password) = desired_credentials(port, state.kube_client.clone()).await?;
+        let generator = UsernameGenerator::from_config();
+        let seed = format!("{name}.{ns}");
+        let generated = match &username {
+            Some(_) => generator.generate_group(state, port, &seed).await?,
+            None => generator.generate_role(state, port, &seed, None).await?,
         };
 
-        Ok(Self { username, password })
+        let username = username.unwrap_or(generated.clone());
+        let password = password.unwrap_or_else(generate_password);
+        let group = format!("{generated}_group");
+
+        Ok(Self {
+            username,
+            password,
+            stats: None,
+            reconcile_at: None,
+            conditions: Vec::new(),
+            sync_lag_seconds: None,
+            network: None,
+            group: Some(group),
+            secondary: None,
+            credentials_expire_at: None,
+            host: state.networks.public_host(port.spec.network.as_str()),
+            throughput_tier: None,
+        })
     }
 }
 
 impl DbSyncPort {
+    pub fn grants(&self) -> Grants {
+        match self.spec.access_profile {
+            AccessProfile::Standard => Grants::port(),
+            AccessProfile::ReadOnly => Grants {
+                access: Access::Read,
+                statement_timeout: get_config().statement_timeout,
+            },
+        }
+    }
+
+    // The canonical name of the network of the spec, which may be one of its aliases
+    pub fn network(&self, state: &State) -> String {
+        state.networks.canonical(self.spec.network.as_str())
+    }
+
+    pub async fn project(&self, state: &State) -> String {
+        let ns = self.namespace().unwrap();
+        state.project(&ns, self.spec.project_ref.as_deref()).await
+    }
+
     async fn reconcile(
         &self,
         state: Arc<State>,
@@ -77,38 +421,450 @@ impl DbSyncPort {
         let client = state.kube_client.clone();
         let ns = self.namespace().unwrap();
         let name = self.name_any();
-        let crds: Api<DbSyncPort> = Api::namespaced(client, &ns);
+        let crds: Api<DbSyncPort> = Api::namespaced(client.clone(), &ns);
 
-        let status = self
-            .status
-            .clone()
-            .unwrap_or(DbSyncPortStatus::try_new(self).await?);
+        let admission = quota::check(&state, self).await?;
+        let pending = quota::is_pending(self);
 
-        if self.status.is_none() {
-            let payload = json!({ "status": status });
+        let mut status = match &self.status {
+            Some(status) => status.clone(),
+            None => DbSyncPortStatus::try_new(self, &state).await?,
+        };
 
-            let patch_params = PatchParams::default();
-            let patch_payload = Patch::Merge(payload);
+        // A new port over the quota of its project keeps its credentials in the status, but
+        // the user is only created once the quota has room for it.
+        if pending {
+            if let Admission::Exceeded(message) = &admission {
+                return self.wait_for_quota(&state, status, message).await;
+            }
 
-            crds.patch_status(&name, &patch_params, &patch_payload)
-                .await?;
+            match &admission {
+                Admission::Within(_) => {
+                    set_condition(
+                        &mut status.conditions,
+                        QUOTA_CONDITION,
+                        false,
+                        "WithinQuota",
+                        String::new(),
+                    );
+                }
+                _ if self.status.is_some() => {
+                    set_condition(
+                        &mut status.conditions,
+                        QUOTA_CONDITION,
+                        false,
+                        "NoQuota",
+                        String::new(),
+                    );
+                }
+                _ => {}
+            }
+            self.patch_pending(&crds, &status).await?;
 
             info!({ status.username }, "user created");
-            state.metrics.count_user_created(&ns, &self.spec.network);
+            state
+                .metrics
+                .count_user_created(&self.project(&state).await, &self.network(&state));
+        }
+
+        // A new value on the annotation forces the grants to be applied again on every instance
+        let reconcile_at = self.annotations().get(RECONCILE_AT_ANNOTATION).cloned();
+        let forced = reconcile_at.is_some() && reconcile_at != status.reconcile_at;
+
+        // A tier or network change over the quota is held back, the port keeps working with the
+        // tier and on the network it has until the quota has room
+        let held_back = matches!(admission, Admission::HeldBack(_));
+        let tier = match held_back {
+            true => Some(quota::applied_tier(self)),
+            false => self.spec.throughput_tier,
+        };
+        let held_connections;
+        let (network, pg_connections) = match (&status.network, held_back) {
+            (Some(old), true) if *old != self.network(&state) => {
+                held_connections = state.get_pg_by_network(old)?;
+                (old.clone(), held_connections.as_slice())
+            }
+            _ => (self.network(&state), pg_connections),
+        };
+        let network = &network;
+
+        // Excluded instances are skipped, the port is ready once the others have the user and
+        // it's provisioned on the excluded ones when they recover.
+        let (available, excluded) = state.instances.available(network, pg_connections);
+
+        // Credentials changes wait for every instance and for a migration to finish, so the old
+        // role isn't left behind on an instance that missed the change.
+        let migrating = status.network.as_deref().is_some_and(|old| old != network);
+        let mut renew_at = None;
+        if self.status.is_some() && excluded.is_empty() && !migrating {
+            self.update_credentials(&state, &mut status, &available)
+                .await?;
+            self.update_secondary(&state, &mut status, &available)
+                .await?;
+            renew_at = self
+                .renew_credentials(&state, &mut status, &available)
+                .await?;
+        }
+
+        let group = self.group(&status);
+        let result = self
+            .provision_roles(&state, &group, &status, &available, forced)
+            .await;
+
+        // Ports created before the group roles have the grants on the user itself, they are
+        // dropped once every instance has the group.
+        let grouped = match (&status.group, &result, excluded.is_empty()) {
+            (None, Ok(()), true) => {
+                future::try_join_all(available.iter().map(|pg| pg.drop_owned(&status.username)))
+                    .await?;
+                Some(group.clone())
+            }
+            _ => status.group.clone(),
+        };
+
+        // A new network is provisioned first, the user only leaves the old one once it's on
+        // every instance of the new network.
+        let migrating_from = status.network.clone().filter(|old| old != network);
+        let migration = match (&migrating_from, &result, excluded.is_empty()) {
+            (Some(old), Ok(()), true) => {
+                Some(self.leave_network(&state, old, &status, &available).await)
+            }
+            _ => None,
+        };
+
+        let mut conditions = status.conditions.clone();
+        let has_quota_condition = conditions.iter().any(|c| c.type_ == QUOTA_CONDITION);
+        match &admission {
+            Admission::Exceeded(message) => {
+                let changed = set_condition(
+                    &mut conditions,
+                    QUOTA_CONDITION,
+                    true,
+                    "OverQuota",
+                    message.clone(),
+                );
+                if changed {
+                    let note = format!("the port is over the quota of its project: {message}");
+                    self.publish_quota_exceeded(&state, note).await;
+                }
+            }
+            Admission::HeldBack(message) => {
+                let changed = set_condition(
+                    &mut conditions,
+                    QUOTA_CONDITION,
+                    true,
+                    "ChangeHeldBack",
+                    message.clone(),
+                );
+                if changed {
+                    let note = format!("the change of the port is over the quota: {message}");
+                    self.publish_quota_exceeded(&state, note).await;
+                }
+            }
+            Admission::Within(_) => {
+                set_condition(
+                    &mut conditions,
+                    QUOTA_CONDITION,
+                    false,
+                    "WithinQuota",
+                    String::new(),
+                );
+            }
+            Admission::Unlimited if has_quota_condition => {
+                set_condition(
+                    &mut conditions,
+                    QUOTA_CONDITION,
+                    false,
+                    "NoQuota",
+                    String::new(),
+                );
+            }
+            Admission::Unlimited => {}
+        }
+        match (&result, available.is_empty(), excluded.is_empty()) {
+            (Err(err), _, _) => set_condition(
+                &mut conditions,
+                READY_CONDITION,
+                false,
+                "ProvisionFailed",
+                err.to_string(),
+            ),
+            (Ok(()), true, _) => set_condition(
+                &mut conditions,
+                READY_CONDITION,
+                false,
+                "NoHealthyInstance",
+                format!("instances excluded: {}", excluded.join(", ")),
+            ),
+            (Ok(()), false, true) if state.networks.is_degraded(network) => set_condition(
+                &mut conditions,
+                READY_CONDITION,
+                true,
+                "NetworkDegraded",
+                format!("network {network} can't be fully served, see its events"),
+            ),
+            (Ok(()), false, true) => set_condition(
+                &mut conditions,
+                READY_CONDITION,
+                true,
+                "Provisioned",
+                String::new(),
+            ),
+            (Ok(()), false, false) => set_condition(
+                &mut conditions,
+                READY_CONDITION,
+                true,
+                "PartiallyProvisioned",
+                format!("pending on excluded instances: {}", excluded.join(", ")),
+            ),
+        };
+        let provisioned_network = match (&migrating_from, &migration) {
+            (None, _) if result.is_ok() => Some(network.to_string()),
+            (Some(old), None) => {
+                set_condition(
+                    &mut conditions,
+                    MIGRATING_CONDITION,
+                    true,
+                    "ProvisioningNewNetwork",
+                    format!("provisioning the user on {network} before leaving {old}"),
+                );
+                status.network.clone()
+            }
+            (Some(old), Some(Err(err))) => {
+                set_condition(
+                    &mut conditions,
+                    MIGRATING_CONDITION,
+                    true,
+                    "LeavingOldNetwork",
+                    format!("fail to drop the user from {old}: {err}"),
+                );
+                status.network.clone()
+            }
+            (Some(old), Some(Ok(()))) => {
+                set_condition(
+                    &mut conditions,
+                    MIGRATING_CONDITION,
+                    false,
+                    "Migrated",
+                    format!("migrated from {old} to {network}"),
+                );
+                Some(network.to_string())
+            }
+            (None, _) => status.network.clone(),
+        };
+        let host = state.networks.public_host(network);
+        if conditions != status.conditions
+            || provisioned_network != status.network
+            || grouped != status.group
+            || host != status.host
+            || tier != status.throughput_tier
+        {
+            update_status(&crds, &name, |current| {
+                let changed =
+                    copy_conditions(&conditions, &mut current.conditions, &RECONCILER_CONDITIONS);
+                let fields = (&current.network, &current.group, &current.host);
+                let unchanged = fields == (&provisioned_network, &grouped, &host)
+                    && current.throughput_tier == tier;
+                current.network = provisioned_network.clone();
+                current.group = grouped.clone();
+                current.host = host.clone();
+                current.throughput_tier = tier;
+                changed || !unchanged
+            })
+            .await?;
+        }
+        result?;
+
+        if let (Some(old), Some(migration)) = (&migrating_from, migration) {
+            migration?;
+
+            let note = format!("user {} migrated from {old} to {network}", status.username);
+            events::publish(
+                &state,
+                self,
+                EventType::Normal,
+                events::NETWORK_MIGRATED,
+                "Migrate",
+                note,
+            )
+            .await;
+            info!(from = old, to = network, "port migrated");
+            let project = self.project(&state).await;
+            state.metrics.count_user_dropped(&project, old);
+            state.metrics.count_user_created(&project, network);
+        }
+
+        if forced {
+            let payload = json!({ "status": { "reconcileAt": reconcile_at } });
+            crds.patch_status(&name, &PatchParams::default(), &Patch::Merge(payload))
+                .await?;
+            info!(reconcile_at, "forced reconcile done");
+        }
+
+        if !excluded.is_empty() || migrating_from.is_some() || state.networks.is_degraded(network) {
+            return Ok(Action::requeue(get_config().instance_check_interval));
+        }
+        if held_back {
+            return Ok(Action::requeue(quota::QUOTA_RECHECK_INTERVAL));
         }
 
-        let tasks = future::join_all(
-            pg_connections
-                .iter()
-                .map(|pg| pg.create_user(&status.username, &status.password)),
+        // The renewal can't wait for the next resync when it's due before
+        let resync_interval = get_config().resync_interval;
+        if let Some(renew_at) = renew_at {
+            let wait = (renew_at - Utc::now()).to_std().unwrap_or_default();
+            if resync_interval.is_zero() || wait < resync_interval {
+                return Ok(Action::requeue(wait));
+            }
+        }
+        Ok(resync_action())
+    }
+
+    // The status of a pending port is written against the version of the port its admission
+    // was decided on, with the quota condition in the same patch. A reconcile that decided on an
+    // older version, before the port was admitted or created, gets a conflict and tries again.
+    async fn patch_pending(
+        &self,
+        crds: &Api<DbSyncPort>,
+        status: &DbSyncPortStatus,
+    ) -> Result<(), Error> {
+        let fields = match &self.status {
+            None => json!(status),
+            Some(_) => json!({ "conditions": status.conditions }),
+        };
+        let payload = json!({
+            "metadata": { "resourceVersion": self.resource_version() },
+            "status": fields
+        });
+        crds.patch_status(
+            &self.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(payload),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn wait_for_quota(
+        &self,
+        state: &State,
+        mut status: DbSyncPortStatus,
+        message: &str,
+    ) -> Result<Action, Error> {
+        let mut conditions = status.conditions.clone();
+        let changed = set_condition(
+            &mut conditions,
+            QUOTA_CONDITION,
+            true,
+            quota::NOT_PROVISIONED_REASON,
+            message.into(),
+        );
+        set_condition(
+            &mut conditions,
+            READY_CONDITION,
+            false,
+            "QuotaExceeded",
+            message.into(),
+        );
+
+        let crds: Api<DbSyncPort> =
+            Api::namespaced(state.kube_client.clone(), &self.namespace().unwrap());
+        if self.status.is_none() {
+            status.conditions = conditions;
+            self.patch_pending(&crds, &status).await?;
+        } else if conditions != status.conditions {
+            update_status(&crds, &self.name_any(), |current| {
+                copy_conditions(&conditions, &mut current.conditions, &RECONCILER_CONDITIONS)
+            })
+            .await?;
+        }
+        if changed {
+            let note = format!("the user isn't created until the quota has room: {message}");
+            self.publish_quota_exceeded(state, note).await;
+        }
+
+        info!(message, "port waiting for quota");
+        Ok(Action::requeue(quota::QUOTA_RECHECK_INTERVAL))
+    }
+
+    async fn publish_quota_exceeded(&self, state: &State, note: String) {
+        events::publish(
+            state,
+            self,
+            EventType::Warning,
+            events::QUOTA_EXCEEDED,
+            "Reconcile",
+            note,
         )
         .await;
+    }
 
-        if tasks.iter().any(Result::is_err) {
-            return Err(Error::PgError("fail to create user".into()));
+    // The old network's connections split into the instances that also serve the new network,
+    // where the role stays, and the ones it must be dropped from.
+    fn old_connections(
+        state: &State,
+        old: &str,
+        pg_connections: &[Postgres],
+    ) -> (Vec<Postgres>, Vec<Postgres>) {
+        let Ok(old_connections) = state.get_pg_by_network(old) else {
+            return (Vec::new(), Vec::new());
+        };
+        old_connections
+            .iter()
+            .cloned()
+            .partition(|pg| pg_connections.iter().any(|new| new.instance == pg.instance))
+    }
+
+    // Creates the group with the grants of the port and its credentials as members, one
+    // instance at a time since the members need the group.
+    async fn provision_roles(
+        &self,
+        state: &State,
+        group: &str,
+        status: &DbSyncPortStatus,
+        pg_connections: &[Postgres],
+        forced: bool,
+    ) -> Result<(), Error> {
+        let grants = self.grants();
+        let members = self.members(status);
+        let usernames = status.usernames().join(", ");
+
+        provision(state, self, &usernames, pg_connections, |pg| async {
+            let mut provision = pg.create_group(group, &grants, forced).await?;
+            for member in members.iter() {
+                let result = pg
+                    .create_member(member, group, grants.statement_timeout, forced)
+                    .await?;
+                provision = provision.merge(result);
+            }
+            Ok(provision)
+        })
+        .await
+    }
+
+    async fn leave_network(
+        &self,
+        state: &State,
+        old: &str,
+        status: &DbSyncPortStatus,
+        pg_connections: &[Postgres],
+    ) -> Result<(), Error> {
+        let group = self.group(status);
+        let (shared, dedicated) = Self::old_connections(state, old, pg_connections);
+
+        drop_roles(state, self, status.usernames(), &group, &dedicated).await?;
+        // The user itself only holds grants on ports created before the group roles
+        for role in [group.as_str(), status.username.as_str()] {
+            future::try_join_all(shared.iter().map(|pg| pg.drop_owned(role))).await?;
         }
 
-        Ok(Action::await_change())
+        // Dropping what the roles own on the old database also revokes their privileges on the
+        // databases of the instance, so the grants of the new network are applied again.
+        if !shared.is_empty() {
+            self.provision_roles(state, &group, status, pg_connections, true)
+                .await?;
+        }
+
+        Ok(())
     }
 
     async fn cleanup(
@@ -116,63 +872,300 @@ impl DbSyncPort {
         state: Arc<State>,
         pg_connections: &[Postgres],
     ) -> Result<Action, Error> {
-        if self.status.is_some() {
+        if let Some(status) = &self.status {
             let ns = self.namespace().unwrap();
-            let username = self.status.as_ref().unwrap().username.clone();
+            let group = self.group(status);
+
+            // A port deleted while migrating still has the user on instances of the old network
+            let network = &self.network(&state);
+            let old = status.network.clone().filter(|old| old != network);
+            let (shared, dedicated) = match &old {
+                Some(old) => Self::old_connections(&state, old, pg_connections),
+                None => (Vec::new(), Vec::new()),
+            };
+
+            // Excluded instances can't be reached, their roles are left to the drop-orphans
+            // command of the admin CLI instead of blocking the deletion
+            let (mut pg_connections, mut excluded) =
+                state.instances.available(network, pg_connections);
+            let (shared, _) = match &old {
+                Some(old) => state.instances.available(old, &shared),
+                None => (shared, Vec::new()),
+            };
+            if let Some(old) = &old {
+                let (dedicated, old_excluded) = state.instances.available(old, &dedicated);
+                pg_connections.extend(dedicated);
+                excluded.extend(old_excluded);
+            }
+            if !excluded.is_empty() {
+                warn!(
+                    username = status.username,
+                    excluded = excluded.join(", "),
+                    "roles left on excluded instances"
+                );
+            }
+            if state.get_pg_by_network(network).is_err() {
+                warn!(
+                    username = status.username,
+                    network, "roles left on an unknown network"
+                );
+            }
 
-            let tasks =
-                future::join_all(pg_connections.iter().map(|pg| pg.drop_user(&username))).await;
-            if tasks.iter().any(Result::is_err) {
-                return Err(Error::PgError("fail to drop user".into()));
+            for role in [group.as_str(), status.username.as_str()] {
+                future::try_join_all(shared.iter().map(|pg| pg.drop_owned(role))).await?;
             }
+            drop_roles(&state, self, status.usernames(), &group, &pg_connections).await?;
 
-            info!({ username }, "user dropped");
-            state.metrics.count_user_dropped(&ns, &self.spec.network);
+            info!(username = status.username, "user dropped");
+            state.metrics.credential_dropped(&ns, &self.name_any());
+            // A port waiting for quota never had its user created
+            if !quota::is_pending(self) {
+                let provisioned = status.network.as_deref().unwrap_or(network);
+                state
+                    .metrics
+                    .count_user_dropped(&self.project(&state).await, provisioned);
+            }
         }
 
         Ok(Action::await_change())
     }
 }
 
+#[instrument(
+    "reconcile",
+    skip_all,
+    fields(
+        namespace = crd.namespace().unwrap_or_default(),
+        name = crd.name_any(),
+        network = crd.spec.network.as_str(),
+        generation = crd.metadata.generation.unwrap_or_default(),
+    )
+)]
 async fn reconcile(crd: Arc<DbSyncPort>, state: Arc<State>) -> Result<Action, Error> {
+    let started = Instant::now();
+    state.health.tick_started(CONTROLLER_LOOP);
+
+    let result = reconcile_port(crd, state.clone()).await;
+
+    state.health.tick_finished(CONTROLLER_LOOP, result.is_ok());
+    state
+        .metrics
+        .reconcile_finished(started.elapsed(), result.is_ok());
+
+    result
+}
+
+async fn reconcile_port(crd: Arc<DbSyncPort>, state: Arc<State>) -> Result<Action, Error> {
     let ns = crd.namespace().unwrap();
     let crds: Api<DbSyncPort> = Api::namespaced(state.kube_client.clone(), &ns);
 
-    let pg_connections = state.get_pg_by_network(&handle_legacy_networks(&crd.spec.network))?;
+    // A port of a network that is gone can still be deleted, its cleanup drops what it can reach
+    let pg_connections = match state.get_pg_by_network(crd.spec.network.as_str()) {
+        Ok(pg_connections) => pg_connections,
+        Err(_) if crd.metadata.deletion_timestamp.is_some() => Vec::new(),
+        Err(err) => {
+            let note = format!("network {} is not supported", crd.spec.network);
+            events::publish(
+                &state,
+                crd.as_ref(),
+                EventType::Warning,
+                events::UNKNOWN_NETWORK,
+                "Reconcile",
+                note,
+            )
+            .await;
+            return Err(err);
+        }
+    };
 
     finalizer(&crds, DB_SYNC_PORT_FINALIZER, crd, |event| async {
         match event {
-            Event::Apply(crd) => crd.reconcile(state.clone(), pg_connections).await,
-            Event::Cleanup(crd) => crd.cleanup(state.clone(), pg_connections).await,
+            Event::Apply(crd) => crd.reconcile(state.clone(), &pg_connections).await,
+            Event::Cleanup(crd) => crd.cleanup(state.clone(), &pg_connections).await,
         }
     })
     .await
     .map_err(|e| Error::FinalizerError(Box::new(e)))
 }
 
-fn error_policy(crd: Arc<DbSyncPort>, error: &Error, state: Arc<State>) -> Action {
-    error!(error = error.to_string(), "reconcile failed");
-    state.metrics.reconcile_failure(&crd, error);
-    Action::requeue(Duration::from_secs(5))
+pub(crate) async fn provision_user<K>(
+    state: &State,
+    resource: &K,
+    username: &str,
+    password: &str,
+    grants: &Grants,
+    pg_connections: &[Postgres],
+    forced: bool,
+) -> Result<(), Error>
+where
+    K: Resource<DynamicType = ()>,
+{
+    provision(state, resource, username, pg_connections, |pg| {
+        pg.create_user(username, password, grants, forced)
+    })
+    .await
+}
+
+// Creates the user where it's missing and applies the grants again where they drifted. Every
+// instance is attempted before failing, so one unreachable replica doesn't block the others. The
+// databases of one instance are provisioned in order, the role is created by the first and the
+// others only apply their grants.
+async fn provision<'a, K, F, Fut>(
+    state: &State,
+    resource: &K,
+    username: &str,
+    pg_connections: &'a [Postgres],
+    create: F,
+) -> Result<(), Error>
+where
+    K: Resource<DynamicType = ()>,
+    F: Fn(&'a Postgres) -> Fut,
+    Fut: Future<Output = Result<Provision, Error>>,
+{
+    let mut instances: Vec<Vec<&'a Postgres>> = Vec::new();
+    for pg in pg_connections.iter() {
+        match instances
+            .iter_mut()
+            .find(|group| group[0].instance == pg.instance)
+        {
+            Some(group) => group.push(pg),
+            None => instances.push(vec![pg]),
+        }
+    }
+    let create = &create;
+    let tasks = future::join_all(instances.into_iter().map(|group| async move {
+        let mut results = Vec::new();
+        for pg in group {
+            results.push((pg, create(pg).await));
+        }
+        results
+    }))
+    .await;
+
+    let mut failed = false;
+    for (pg, result) in tasks.into_iter().flatten() {
+        let instance = &pg.instance;
+        if let Some((type_, reason, note)) = events::provision_event(&result, username, instance) {
+            events::publish(state, resource, type_, reason, "Provision", note).await;
+        }
+        match result {
+            Ok(_) => {}
+            Err(err @ Error::PgUnreachable(_)) => {
+                error!(error = err.to_string(), instance, "postgres unreachable");
+                failed = true;
+            }
+            Err(err) => {
+                error!(error = err.to_string(), instance, "fail to create user");
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        return Err(Error::PgError("fail to create user".into()));
+    }
+
+    Ok(())
 }
 
-async fn gen_username_hash(username: &str) -> Result<String, Error> {
-    let mut hasher = Sha3_256::new();
-    hasher.update(username);
-    let sha256_hash = hasher.finalize();
+// The members are dropped before the group, each once its sessions are terminated
+async fn drop_roles<K>(
+    state: &State,
+    resource: &K,
+    usernames: Vec<&str>,
+    group: &str,
+    pg_connections: &[Postgres],
+) -> Result<(), Error>
+where
+    K: Resource<DynamicType = ()>,
+{
+    for username in usernames.iter() {
+        terminate_sessions(state, resource, username, pg_connections).await?;
+    }
 
-    let bech32_hash = bech32::encode(
-        "dmtr_dbsync",
-        sha256_hash.to_base32(),
-        bech32::Variant::Bech32,
-    )?;
+    for role in usernames.into_iter().chain([group]) {
+        let tasks = future::join_all(pg_connections.iter().map(|pg| pg.drop_user(role))).await;
+        if tasks.iter().any(Result::is_err) {
+            return Err(Error::PgError("fail to drop user".into()));
+        }
+    }
+    Ok(())
+}
+
+// A user is only dropped once it has no sessions left. The sessions found are terminated and the
+// cleanup fails, so the finalizer retries the drop on the next reconcile.
+pub(crate) async fn terminate_sessions<K>(
+    state: &State,
+    resource: &K,
+    username: &str,
+    pg_connections: &[Postgres],
+) -> Result<(), Error>
+where
+    K: Resource<DynamicType = ()>,
+{
+    let sessions =
+        future::try_join_all(pg_connections.iter().map(|pg| pg.active_sessions(username))).await?;
+    let active: i64 = sessions.iter().sum();
+    if active == 0 {
+        return Ok(());
+    }
+
+    future::try_join_all(
+        pg_connections
+            .iter()
+            .map(|pg| pg.terminate_sessions(username)),
+    )
+    .await?;
+
+    let note = format!("user {username} has {active} active sessions, terminating");
+    events::publish(
+        state,
+        resource,
+        EventType::Warning,
+        events::DELETION_BLOCKED,
+        "Cleanup",
+        note,
+    )
+    .await;
+    Err(Error::PgError("user has active sessions".into()))
+}
 
-    let bech32_truncated: String = bech32_hash.chars().take(32).collect();
+// Periodic resync recreates users dropped by hand or lost in a restore. The jitter spreads the
+// requeues of the ports created together, so they don't hit postgres at the same time.
+pub(crate) fn resync_action() -> Action {
+    let config = get_config();
+    if config.resync_interval.is_zero() {
+        return Action::await_change();
+    }
 
-    Ok(bech32_truncated)
+    let jitter = config.resync_jitter;
+    let factor = 1. + rand::thread_rng().gen_range(-jitter..=jitter);
+    Action::requeue(config.resync_interval.mul_f64(factor))
+}
+
+fn error_policy(crd: Arc<DbSyncPort>, error: &Error, state: Arc<State>) -> Action {
+    error!(error = error.to_string(), "reconcile failed");
+    let ns = crd.namespace().unwrap();
+    let project = state.cached_project(&ns, crd.spec.project_ref.as_deref());
+    state
+        .metrics
+        .reconcile_failure(crd.as_ref(), &project, error);
+    Action::requeue(Duration::from_secs(5))
 }
 
 #[instrument("controller run", skip_all)]
+// The status patches of the reconciler and of the collectors don't trigger a reconcile, a spec
+// change, a deletion or a forced reconcile do. The resync covers the rest.
+fn reconcile_trigger(crd: &DbSyncPort) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    crd.metadata.generation.hash(&mut hasher);
+    crd.metadata.deletion_timestamp.is_some().hash(&mut hasher);
+    crd.annotations()
+        .get(RECONCILE_AT_ANNOTATION)
+        .hash(&mut hasher);
+    Some(hasher.finish())
+}
+
 pub async fn run(state: Arc<State>) {
     info!("listening crds running");
 
@@ -186,10 +1179,132 @@ pub async fn run(state: Arc<State>) {
         std::process::exit(1);
     }
 
-    Controller::new(crds, WatcherConfig::default().any_semantic())
-        .shutdown_on_signal()
-        .run(reconcile, error_policy, state)
-        .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
-        .await;
+    let internal_users = Api::<InternalDbUser>::all(client.clone());
+    if let Err(e) = internal_users.list(&ListParams::default().limit(1)).await {
+        error!("InternalDbUser CRD is not queryable; {e:?}. Is the CRD installed?");
+        std::process::exit(1);
+    }
+
+    loop {
+        tokio::select! {
+            _ = state.leadership.acquired() => {},
+            _ = state.shutdown.wait() => return,
+        }
+        info!("leadership acquired, reconciling crds");
+
+        // The store follows every change, the reconciles only the ones of reconcile_trigger.
+        // Graceful shutdown stops new reconciles and waits for the running ones to finish
+        let (store, writer) = reflector::store();
+        let stream = watcher(crds.clone(), WatcherConfig::default().any_semantic())
+            .default_backoff()
+            .reflect(writer)
+            .applied_objects()
+            .predicate_filter(reconcile_trigger);
+        let controller = Controller::for_stream(stream, store);
+        state.ports.set(Some(controller.store()));
+        let ports = controller
+            .graceful_shutdown_on(state.shutdown.wait())
+            .graceful_shutdown_on(state.leadership.lost())
+            .run(reconcile, error_policy, state.clone())
+            .filter_map(|x| async move { std::result::Result::ok(x) })
+            .for_each(|_| futures::future::ready(()));
+
+        tokio::join!(
+            ports,
+            internal_user::run_controller(state.clone(), internal_users.clone())
+        );
+
+        state.ports.set(None);
+        if state.shutdown.is_triggered() {
+            info!("controller stopped");
+            return;
+        }
+        info!("leadership lost, reconcile stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+    #[test]
+    fn test_system_role() {
+        assert!(is_system_role("postgres"));
+        assert!(is_system_role("pg_monitor"));
+        assert!(!is_system_role("postgrest"));
+        assert!(!is_system_role("dmtr_blockfrost"));
+    }
+
+    #[test]
+    fn test_copy_conditions() {
+        let mut reconciled = Vec::new();
+        set_condition(
+            &mut reconciled,
+            READY_CONDITION,
+            true,
+            "Provisioned",
+            String::new(),
+        );
+        set_condition(
+            &mut reconciled,
+            "ChainSynced",
+            false,
+            "Lagging",
+            String::new(),
+        );
+
+        let mut current = Vec::new();
+        set_condition(
+            &mut current,
+            READY_CONDITION,
+            false,
+            "ProvisionFailed",
+            String::new(),
+        );
+        set_condition(&mut current, "ChainSynced", true, "Synced", String::new());
+
+        assert!(copy_conditions(
+            &reconciled,
+            &mut current,
+            &RECONCILER_CONDITIONS
+        ));
+        assert!(!copy_conditions(
+            &reconciled,
+            &mut current,
+            &RECONCILER_CONDITIONS
+        ));
+        let reason = |type_: &str| {
+            let condition = current.iter().find(|c| c.type_ == type_).unwrap();
+            condition.reason.clone()
+        };
+        assert_eq!(reason(READY_CONDITION), "Provisioned");
+        assert_eq!(reason("ChainSynced"), "Synced");
+    }
+
+    #[test]
+    fn test_reconcile_trigger() {
+        let mut port = fixtures::port("port", "cardano-mainnet");
+        port.metadata.generation = Some(1);
+        let trigger = reconcile_trigger(&port);
+
+        let mut stats = fixtures::provisioned(port.clone(), &["dmtr_dbsync_port"]);
+        stats.status.as_mut().unwrap().sync_lag_seconds = Some(10);
+        assert_eq!(reconcile_trigger(&stats), trigger);
+
+        let mut changed = port.clone();
+        changed.metadata.generation = Some(2);
+        assert_ne!(reconcile_trigger(&changed), trigger);
+
+        let mut forced = port.clone();
+        forced
+            .annotations_mut()
+            .insert(RECONCILE_AT_ANNOTATION.to_string(), "1".to_string());
+        assert_ne!(reconcile_trigger(&forced), trigger);
+
+        let mut deleted = port.clone();
+        deleted.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        assert_ne!(reconcile_trigger(&deleted), trigger);
+    }
 }
@@ -0,0 +1,287 @@
+use kube::{
+    core::{
+        conversion::{ConversionRequest, ConversionResponse},
+        response::Status,
+    },
+    ResourceExt,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+use crate::{
+    controller::{
+        AccessProfile, CredentialsSecretRef, DbSyncPort, DbSyncPortSpec, Network,
+        SecondaryCredential, MAX_THROUGHPUT_TIER,
+    },
+    v1alpha1,
+};
+
+pub const V1ALPHA1: &str = "demeter.run/v1alpha1";
+pub const V1BETA1: &str = "demeter.run/v1beta1";
+
+// Fields that only exist in one version are kept in an annotation while the object is read in
+// the other one, so converting back and forth doesn't lose anything.
+pub const V1ALPHA1_ANNOTATION: &str = "conversion.demeter.run/v1alpha1";
+pub const V1BETA1_ANNOTATION: &str = "conversion.demeter.run/v1beta1";
+
+#[derive(Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct V1Alpha1Fields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    network: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    throughput_tier: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct V1Beta1Fields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    credentials_secret_ref: Option<CredentialsSecretRef>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    access_profile: Option<AccessProfile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secondary_credential: Option<SecondaryCredential>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    credential_ttl: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    project_ref: Option<String>,
+}
+
+// The inline password of a port created through v1alpha1
+pub fn legacy_password(port: &DbSyncPort) -> Option<String> {
+    let value = port.annotations().get(V1ALPHA1_ANNOTATION)?;
+    serde_json::from_str::<V1Alpha1Fields>(value).ok()?.password
+}
+
+fn take_annotation<T: DeserializeOwned + Default>(obj: &mut Value, key: &str) -> Result<T, String> {
+    let Some(annotations) = obj
+        .pointer_mut("/metadata/annotations")
+        .and_then(Value::as_object_mut)
+    else {
+        return Ok(T::default());
+    };
+
+    let value = annotations.remove(key);
+    if annotations.is_empty() {
+        obj["metadata"]
+            .as_object_mut()
+            .unwrap()
+            .remove("annotations");
+    }
+
+    match value {
+        Some(Value::String(value)) => {
+            serde_json::from_str(&value).map_err(|err| format!("invalid {key} annotation: {err}"))
+        }
+        _ => Ok(T::default()),
+    }
+}
+
+fn put_annotation<T: Serialize + Default + PartialEq>(obj: &mut Value, key: &str, fields: &T) {
+    if *fields == T::default() {
+        return;
+    }
+
+    let metadata = obj["metadata"].as_object_mut().unwrap();
+    let annotations = metadata.entry("annotations").or_insert(json!({}));
+    annotations[key] = Value::String(serde_json::to_string(fields).unwrap());
+}
+
+fn take_spec<T: DeserializeOwned>(obj: &mut Value) -> Result<T, String> {
+    serde_json::from_value(obj["spec"].take()).map_err(|err| format!("invalid spec: {err}"))
+}
+
+pub fn to_v1beta1(mut obj: Value) -> Result<Value, String> {
+    let spec: v1alpha1::DbSyncPortSpec = take_spec(&mut obj)?;
+    let beta: V1Beta1Fields = take_annotation(&mut obj, V1BETA1_ANNOTATION)?;
+    let mut alpha = V1Alpha1Fields::default();
+
+    let network = Network::from_str(&spec.network).map_err(|err| err.to_string())?;
+    if network.as_str() != spec.network {
+        alpha.network = Some(spec.network);
+    }
+
+    // A tier that isn't a number would be metered at tier 0, so the conversion fails instead
+    let throughput_tier = match spec.throughput_tier {
+        Some(tier) => match tier.parse::<u32>() {
+            Ok(parsed) if parsed <= MAX_THROUGHPUT_TIER => {
+                if parsed.to_string() != tier {
+                    alpha.throughput_tier = Some(tier);
+                }
+                Some(parsed)
+            }
+            _ => {
+                return Err(format!(
+                    "throughputTier {tier} must be a number from 0 to {MAX_THROUGHPUT_TIER}"
+                ))
+            }
+        },
+        None => None,
+    };
+    alpha.password = spec.password;
+
+    let spec = DbSyncPortSpec {
+        network,
+        throughput_tier,
+        username: spec.username,
+        credentials_secret_ref: beta.credentials_secret_ref,
+        access_profile: beta.access_profile.unwrap_or_default(),
+        expires_at: beta.expires_at,
+        secondary_credential: beta.secondary_credential,
+        credential_ttl: beta.credential_ttl,
+        project_ref: beta.project_ref,
+    };
+
+    obj["spec"] = serde_json::to_value(spec).unwrap();
+    obj["apiVersion"] = V1BETA1.into();
+    put_annotation(&mut obj, V1ALPHA1_ANNOTATION, &alpha);
+    Ok(obj)
+}
+
+pub fn to_v1alpha1(mut obj: Value) -> Result<Value, String> {
+    let spec: DbSyncPortSpec = take_spec(&mut obj)?;
+    let alpha: V1Alpha1Fields = take_annotation(&mut obj, V1ALPHA1_ANNOTATION)?;
+
+    let beta = V1Beta1Fields {
+        credentials_secret_ref: spec.credentials_secret_ref,
+        access_profile: Some(spec.access_profile).filter(|p| *p != AccessProfile::default()),
+        expires_at: spec.expires_at,
+        secondary_credential: spec.secondary_credential,
+        credential_ttl: spec.credential_ttl,
+        project_ref: spec.project_ref,
+    };
+
+    let spec = v1alpha1::DbSyncPortSpec {
+        network: alpha.network.unwrap_or(spec.network.to_string()),
+        throughput_tier: alpha
+            .throughput_tier
+            .or(spec.throughput_tier.map(|tier| tier.to_string())),
+        username: spec.username,
+        password: alpha.password,
+    };
+
+    obj["spec"] = serde_json::to_value(spec).unwrap();
+    obj["apiVersion"] = V1ALPHA1.into();
+    put_annotation(&mut obj, V1BETA1_ANNOTATION, &beta);
+    Ok(obj)
+}
+
+fn convert_object(obj: Value, desired_api_version: &str) -> Result<Value, String> {
+    let api_version = obj["apiVersion"].as_str().unwrap_or_default();
+    match (api_version, desired_api_version) {
+        (from, to) if from == to => Ok(obj),
+        (V1ALPHA1, V1BETA1) => to_v1beta1(obj),
+        (V1BETA1, V1ALPHA1) => to_v1alpha1(obj),
+        (from, to) => Err(format!("conversion from {from} to {to} is not supported")),
+    }
+}
+
+pub fn convert(request: ConversionRequest) -> ConversionResponse {
+    let desired_api_version = request.desired_api_version.clone();
+
+    let converted: Result<Vec<Value>, String> = request
+        .objects
+        .iter()
+        .cloned()
+        .map(|obj| convert_object(obj, &desired_api_version))
+        .collect();
+
+    let response = ConversionResponse::for_request(request);
+    match converted {
+        Ok(objects) => response.success(objects),
+        Err(err) => response.failure(Status::failure(&err, "ConversionFailed")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alpha(spec: Value) -> Value {
+        json!({
+            "apiVersion": V1ALPHA1,
+            "kind": "DbSyncPort",
+            "metadata": { "name": "port", "namespace": "prj-test" },
+            "spec": spec,
+            "status": { "username": "dmtr_dbsync1abc", "password": "secret" }
+        })
+    }
+
+    #[test]
+    fn test_v1alpha1_round_trip() {
+        let objects = [
+            alpha(json!({ "network": "cardano-mainnet", "throughputTier": "1" })),
+            alpha(json!({ "network": "mainnet", "username": "user", "password": "pass" })),
+            alpha(json!({ "network": "prime-testnet", "throughputTier": "01" })),
+        ];
+
+        for obj in objects {
+            let beta = to_v1beta1(obj.clone()).unwrap();
+            assert_eq!(beta["apiVersion"], V1BETA1);
+            serde_json::from_value::<DbSyncPort>(beta.clone()).unwrap();
+
+            let back = to_v1alpha1(beta).unwrap();
+            assert_eq!(
+                serde_json::from_value::<v1alpha1::DbSyncPort>(back).unwrap(),
+                serde_json::from_value::<v1alpha1::DbSyncPort>(obj).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_v1beta1_round_trip() {
+        let obj = json!({
+            "apiVersion": V1BETA1,
+            "kind": "DbSyncPort",
+            "metadata": { "name": "port", "namespace": "prj-test", "annotations": { "a": "b" } },
+            "spec": {
+                "network": "vector-testnet",
+                "throughputTier": 2,
+                "credentialsSecretRef": { "name": "port-credentials" },
+                "accessProfile": "readOnly",
+                "expiresAt": "2026-01-01T00:00:00Z",
+                "secondaryCredential": { "expiresAt": "2026-02-01T00:00:00Z" },
+                "credentialTtl": "30d",
+                "projectRef": "test"
+            }
+        });
+
+        let alpha = to_v1alpha1(obj.clone()).unwrap();
+        assert_eq!(alpha["spec"]["network"], "vector-testnet");
+        assert_eq!(alpha["spec"]["throughputTier"], "2");
+
+        let back = to_v1beta1(alpha).unwrap();
+        assert_eq!(
+            serde_json::from_value::<DbSyncPort>(back).unwrap(),
+            serde_json::from_value::<DbSyncPort>(obj).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_convert_invalid_tier() {
+        let obj = alpha(json!({ "network": "preview", "throughputTier": "01" }));
+        let beta = convert_object(obj, V1BETA1).unwrap();
+        assert_eq!(beta["spec"]["throughputTier"], 1);
+
+        for tier in ["high", "4", "-1"] {
+            let obj = alpha(json!({ "network": "preview", "throughputTier": tier }));
+            assert!(convert_object(obj, V1BETA1).is_err());
+        }
+    }
+
+    // Networks outside the catalogue are reported by the reconcile, only invalid names fail here
+    #[test]
+    fn test_convert_invalid_network() {
+        let obj = alpha(json!({ "network": "cardano-sidechain" }));
+        assert!(convert_object(obj, V1BETA1).is_ok());
+
+        let obj = alpha(json!({ "network": "Cardano Mainnet" }));
+        assert!(convert_object(obj, V1BETA1).is_err());
+    }
+}
@@ -1,18 +1,77 @@
-use ext_cardano_dbsync::controller;
-use kube::CustomResourceExt;
+use ext_cardano_dbsync::{controller, internal_user, networks, v1alpha1};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceConversion, CustomResourceDefinition, ServiceReference, WebhookClientConfig,
+    WebhookConversion,
+};
+use kube::{core::crd::merge_crds, CustomResourceExt};
+
+fn ports_crd() -> CustomResourceDefinition {
+    let mut crd = merge_crds(
+        vec![v1alpha1::DbSyncPort::crd(), controller::DbSyncPort::crd()],
+        "v1beta1",
+    )
+    .unwrap();
+
+    let namespace = std::env::var("CONVERSION_SERVICE_NAMESPACE").unwrap_or("ext-dbsync-m1".into());
+    let name = std::env::var("CONVERSION_SERVICE_NAME").unwrap_or("operator-webhook".into());
+
+    // The CA bundle is injected by cert-manager from the certificate served by the webhook
+    crd.metadata.annotations = Some(
+        [(
+            "cert-manager.io/inject-ca-from".to_string(),
+            format!("{namespace}/{name}"),
+        )]
+        .into(),
+    );
+    crd.spec.conversion = Some(CustomResourceConversion {
+        strategy: "Webhook".into(),
+        webhook: Some(WebhookConversion {
+            conversion_review_versions: vec!["v1".into()],
+            client_config: Some(WebhookClientConfig {
+                service: Some(ServiceReference {
+                    name,
+                    namespace,
+                    path: Some("/convert".into()),
+                    port: Some(9443),
+                }),
+                ..Default::default()
+            }),
+        }),
+    });
+
+    crd
+}
+
+fn render(json: bool) -> String {
+    let crds = [
+        ports_crd(),
+        internal_user::InternalDbUser::crd(),
+        networks::DbSyncNetwork::crd(),
+    ];
+
+    if json {
+        return serde_json::to_string_pretty(&crds).unwrap();
+    }
+
+    let docs: Vec<String> = crds
+        .iter()
+        .map(|crd| serde_yaml::to_string(crd).unwrap())
+        .collect();
+    docs.join("---\n")
+}
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 && args[1] == "json" {
-        print!(
-            "{}",
-            serde_json::to_string_pretty(&controller::DbSyncPort::crd()).unwrap()
-        );
-        return;
-    }
+    print!("{}", render(args.len() > 1 && args[1] == "json"))
+}
 
-    print!(
-        "{}",
-        serde_yaml::to_string(&controller::DbSyncPort::crd()).unwrap()
-    )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // yaml/crd.yaml is the snapshot, regenerate it with `cargo run --bin=crdgen > yaml/crd.yaml`
+    #[test]
+    fn test_crd_snapshot() {
+        assert_eq!(render(false), include_str!("../yaml/crd.yaml"));
+    }
 }
@@ -0,0 +1,649 @@
+use chrono::{DateTime, Duration, Utc};
+use futures::future;
+use k8s_openapi::api::core::v1::Secret;
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::events::EventType,
+    Api, Client, Resource, ResourceExt,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    controller::{
+        is_superuser, is_system_role, is_valid_username, terminate_sessions, CredentialsSecretRef,
+        SecondaryCredentialStatus, MIN_PASSWORD_LENGTH, USERNAME_PATTERN,
+    },
+    conversion, events, get_config,
+    postgres::{Member, Postgres},
+    username::UsernameGenerator,
+    DbSyncPort, DbSyncPortStatus, Error, State,
+};
+
+pub fn generate_password() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+}
+
+// A failed renewal is retried well before the password expires
+const RENEWAL_RETRY: Duration = Duration::minutes(5);
+
+// The `h` and `d` durations of credentialTtl, the schema rejects anything else
+pub fn parse_ttl(ttl: &str) -> Result<Duration, Error> {
+    let invalid = || Error::ConfigError(format!("invalid credentialTtl {ttl}"));
+    let (value, unit) = ttl.split_at(ttl.len().saturating_sub(1));
+    let value: i64 = value.parse().map_err(|_| invalid())?;
+    match unit {
+        "h" => Ok(Duration::hours(value)),
+        "d" => Ok(Duration::days(value)),
+        _ => Err(invalid()),
+    }
+}
+
+// The password is renewed when a third of its lifetime is left
+fn renewal_time(expires_at: DateTime<Utc>, ttl: Duration) -> DateTime<Utc> {
+    expires_at - ttl / 3
+}
+
+// The username and password set on the port, from the spec or the credentials Secret. The ones
+// not set are generated when the port is created and kept afterwards.
+pub(crate) async fn desired_credentials(
+    port: &DbSyncPort,
+    client: Client,
+) -> Result<(Option<String>, Option<String>), Error> {
+    let ns = port.namespace().unwrap();
+    let secret_ref = port.spec.credentials_secret_ref.as_ref();
+    let (secret_username, secret_password) = read_credentials(client, &ns, secret_ref).await?;
+
+    let username = port.spec.username.clone().or(secret_username);
+    if let Some(username) = &username {
+        check_username(username).map_err(Error::ConfigError)?;
+    }
+    // Ports created through v1alpha1 keep their inline password in an annotation
+    let password = match secret_password {
+        Some(password) => Some(password),
+        None => conversion::legacy_password(port)
+            .map(|password| check_password(password, "the v1alpha1 password of the port"))
+            .transpose()?,
+    };
+    Ok((username, password))
+}
+
+// The schema only checks the pattern. A port can't take over a role of postgres or of an internal
+// service.
+fn check_username(username: &str) -> Result<(), String> {
+    if !is_valid_username(username) {
+        return Err(format!(
+            "the username {username} must match {USERNAME_PATTERN}"
+        ));
+    }
+    if is_system_role(username) || get_config().internal_users.contains_key(username) {
+        return Err(format!("the username {username} is reserved"));
+    }
+    Ok(())
+}
+
+// The schema can't validate a Secret or the annotation of a converted port, so their passwords
+// are checked here
+fn check_password(password: String, source: &str) -> Result<String, Error> {
+    if password.len() < MIN_PASSWORD_LENGTH {
+        return Err(Error::ConfigError(format!(
+            "{source} must have at least {MIN_PASSWORD_LENGTH} characters"
+        )));
+    }
+    Ok(password)
+}
+
+async fn read_credentials(
+    client: Client,
+    namespace: &str,
+    secret_ref: Option<&CredentialsSecretRef>,
+) -> Result<(Option<String>, Option<String>), Error> {
+    let Some(CredentialsSecretRef { name }) = secret_ref else {
+        return Ok((None, None));
+    };
+
+    let secret = Api::<Secret>::namespaced(client, namespace)
+        .get(name)
+        .await?;
+    let data = secret.data.unwrap_or_default();
+
+    let read = |key: &str| {
+        data.get(key)
+            .map(|value| String::from_utf8_lossy(&value.0).to_string())
+    };
+
+    let password = read("password").ok_or(Error::ConfigError(format!(
+        "secret {name} doesn't have a password"
+    )))?;
+    let password = check_password(password, &format!("the password in secret {name}"))?;
+    let username = read("username");
+    if let Some(username) = &username {
+        check_username(username)
+            .map_err(|err| Error::ConfigError(format!("{err}, in secret {name}")))?;
+    }
+    Ok((username, Some(password)))
+}
+
+impl DbSyncPortStatus {
+    // Login roles of the port, every one of them is metered as the port
+    pub fn usernames(&self) -> Vec<&str> {
+        let mut usernames = vec![self.username.as_str()];
+        if let Some(secondary) = &self.secondary {
+            usernames.push(secondary.username.as_str());
+        }
+        usernames
+    }
+}
+
+impl DbSyncPort {
+    // Ports created before the group roles don't have it in the status yet
+    pub fn group(&self, status: &DbSyncPortStatus) -> String {
+        match &status.group {
+            Some(group) => group.clone(),
+            None => {
+                let seed = format!("{}.{}", self.name_any(), self.namespace().unwrap());
+                let generated = UsernameGenerator::from_config().candidate(&seed, 0);
+                format!("{generated}_group")
+            }
+        }
+    }
+
+    pub fn members(&self, status: &DbSyncPortStatus) -> Vec<Member> {
+        // The earliest of the fixed expiry and the one from the credentialTtl applies
+        let valid_until = [&self.spec.expires_at, &status.credentials_expire_at]
+            .into_iter()
+            .flatten()
+            .min_by_key(|time| DateTime::parse_from_rfc3339(time).ok())
+            .cloned();
+
+        let mut members = vec![Member {
+            username: status.username.clone(),
+            password: status.password.clone(),
+            valid_until,
+        }];
+
+        let secondary = self.spec.secondary_credential.as_ref();
+        if let (Some(spec), Some(secondary)) = (secondary, &status.secondary) {
+            members.push(Member {
+                username: secondary.username.clone(),
+                password: secondary.password.clone(),
+                valid_until: spec.expires_at.clone(),
+            });
+        }
+        members
+    }
+
+    pub(crate) async fn update_credentials(
+        &self,
+        state: &State,
+        status: &mut DbSyncPortStatus,
+        pg_connections: &[Postgres],
+    ) -> Result<(), Error> {
+        let (username, password) = desired_credentials(self, state.kube_client.clone()).await?;
+        let username = username.unwrap_or(status.username.clone());
+        let password = password.unwrap_or(status.password.clone());
+
+        let current = (status.username.as_str(), status.password.as_str());
+        let desired = (username.as_str(), password.as_str());
+        let group = self.group(status);
+        let Some(note) =
+            change_credentials(state, self, &group, current, desired, pg_connections).await?
+        else {
+            return Ok(());
+        };
+
+        let payload = json!({ "status": { "username": username, "password": password } });
+        self.patch_status(state, payload).await?;
+
+        info!(previous = status.username, username, "credentials changed");
+        self.publish_rotated(state, note).await;
+
+        status.username = username;
+        status.password = password;
+        Ok(())
+    }
+
+    // The secondary credential is a second login role in the group of the port, created and
+    // retired independently of the primary one.
+    pub(crate) async fn update_secondary(
+        &self,
+        state: &State,
+        status: &mut DbSyncPortStatus,
+        pg_connections: &[Postgres],
+    ) -> Result<(), Error> {
+        let ns = self.namespace().unwrap();
+
+        let (secondary, note) = match (&self.spec.secondary_credential, &status.secondary) {
+            (None, None) => return Ok(()),
+            (None, Some(current)) => {
+                let username = &current.username;
+                terminate_sessions(state, self, username, pg_connections).await?;
+                future::try_join_all(pg_connections.iter().map(|pg| pg.drop_user(username)))
+                    .await?;
+                (None, format!("secondary credential {username} retired"))
+            }
+            (Some(spec), None) => {
+                let secret_ref = spec.credentials_secret_ref.as_ref();
+                let (username, password) =
+                    read_credentials(state.kube_client.clone(), &ns, secret_ref).await?;
+
+                let username = match username {
+                    Some(username) => username,
+                    None => {
+                        let seed = format!("{}.{ns}.secondary", self.name_any());
+                        let group = self.group(status);
+                        UsernameGenerator::from_config()
+                            .generate_role(state, self, &seed, Some(&group))
+                            .await?
+                    }
+                };
+                let password = password.unwrap_or_else(generate_password);
+
+                let note = format!("secondary credential {username} added");
+                (Some(SecondaryCredentialStatus { username, password }), note)
+            }
+            (Some(spec), Some(current)) => {
+                let secret_ref = spec.credentials_secret_ref.as_ref();
+                let (username, password) =
+                    read_credentials(state.kube_client.clone(), &ns, secret_ref).await?;
+                let username = username.unwrap_or(current.username.clone());
+                let password = password.unwrap_or(current.password.clone());
+
+                let desired = (username.as_str(), password.as_str());
+                let current = (current.username.as_str(), current.password.as_str());
+                let group = self.group(status);
+                let Some(note) =
+                    change_credentials(state, self, &group, current, desired, pg_connections)
+                        .await?
+                else {
+                    return Ok(());
+                };
+                (Some(SecondaryCredentialStatus { username, password }), note)
+            }
+        };
+
+        self.patch_status(state, json!({ "status": { "secondary": secondary } }))
+            .await?;
+
+        info!(note, "secondary credential changed");
+        self.publish_rotated(state, note).await;
+
+        status.secondary = secondary;
+        Ok(())
+    }
+
+    // With a credentialTtl the primary role has a VALID UNTIL and a new password is set before
+    // it's reached. When the renewal fails the role expires and can't log in anymore. Returns when
+    // the next renewal is due.
+    pub(crate) async fn renew_credentials(
+        &self,
+        state: &State,
+        status: &mut DbSyncPortStatus,
+        pg_connections: &[Postgres],
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        let ns = self.namespace().unwrap();
+        let name = self.name_any();
+
+        let Some(ttl) = &self.spec.credential_ttl else {
+            if status.credentials_expire_at.is_some() {
+                let payload = json!({ "status": { "credentialsExpireAt": null } });
+                self.patch_status(state, payload).await?;
+                status.credentials_expire_at = None;
+                state.metrics.credential_dropped(&ns, &name);
+            }
+            return Ok(None);
+        };
+        let ttl = parse_ttl(ttl)?;
+
+        let now = Utc::now();
+        let expires_at = status
+            .credentials_expire_at
+            .as_deref()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| time.with_timezone(&Utc));
+
+        match expires_at {
+            // The current password starts the ttl, a shorter ttl brings the expiry forward
+            None => return self.set_expiry(state, status, now + ttl, ttl).await,
+            Some(expires_at) if expires_at > now + ttl => {
+                return self.set_expiry(state, status, now + ttl, ttl).await
+            }
+            Some(expires_at) if now < renewal_time(expires_at, ttl) => {
+                state.metrics.credential_expires(&ns, &name, expires_at);
+                return Ok(Some(renewal_time(expires_at, ttl)));
+            }
+            Some(_) => {}
+        }
+
+        let expires_at = now + ttl;
+        match self
+            .renew_password(state, status, expires_at, pg_connections)
+            .await
+        {
+            Ok(()) => {
+                info!(username = status.username, "password renewed");
+                let project = self.project(state).await;
+                state.metrics.count_credential_renewal(&project, true);
+                state.metrics.credential_expires(&ns, &name, expires_at);
+
+                let note = format!(
+                    "password of user {} renewed, it expires at {}",
+                    status.username,
+                    expires_at.to_rfc3339()
+                );
+                events::publish(
+                    state,
+                    self,
+                    EventType::Normal,
+                    events::CREDENTIALS_RENEWED,
+                    "Renew",
+                    note,
+                )
+                .await;
+                Ok(Some(renewal_time(expires_at, ttl)))
+            }
+            Err(err) => {
+                warn!(error = err.to_string(), "fail to renew password");
+                let project = self.project(state).await;
+                state.metrics.count_credential_renewal(&project, false);
+
+                let note = format!(
+                    "password of user {} expires at {} and couldn't be renewed: {err}",
+                    status.username,
+                    status.credentials_expire_at.clone().unwrap_or_default()
+                );
+                events::publish(
+                    state,
+                    self,
+                    EventType::Warning,
+                    events::CREDENTIAL_EXPIRING,
+                    "Renew",
+                    note,
+                )
+                .await;
+                Ok(Some(now + RENEWAL_RETRY))
+            }
+        }
+    }
+
+    async fn set_expiry(
+        &self,
+        state: &State,
+        status: &mut DbSyncPortStatus,
+        expires_at: DateTime<Utc>,
+        ttl: Duration,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        let payload = json!({ "status": { "credentialsExpireAt": expires_at.to_rfc3339() } });
+        self.patch_status(state, payload).await?;
+
+        status.credentials_expire_at = Some(expires_at.to_rfc3339());
+        let ns = self.namespace().unwrap();
+        state
+            .metrics
+            .credential_expires(&ns, &self.name_any(), expires_at);
+        Ok(Some(renewal_time(expires_at, ttl)))
+    }
+
+    // The new password is written to the credentials Secret first, so a renewal that fails
+    // halfway is finished by the next reconcile as a password change.
+    async fn renew_password(
+        &self,
+        state: &State,
+        status: &mut DbSyncPortStatus,
+        expires_at: DateTime<Utc>,
+        pg_connections: &[Postgres],
+    ) -> Result<(), Error> {
+        let ns = self.namespace().unwrap();
+        let secret_ref = self.spec.credentials_secret_ref.as_ref();
+        if secret_ref.is_none() && conversion::legacy_password(self).is_some() {
+            return Err(Error::ConfigError(
+                "the inline password of a v1alpha1 port can't be renewed, move it to a Secret"
+                    .into(),
+            ));
+        }
+
+        let password = generate_password();
+        if let Some(CredentialsSecretRef { name }) = secret_ref {
+            let secrets = Api::<Secret>::namespaced(state.kube_client.clone(), &ns);
+            let payload = json!({ "stringData": { "password": password } });
+            secrets
+                .patch(name, &PatchParams::default(), &Patch::Merge(payload))
+                .await?;
+        }
+
+        future::try_join_all(
+            pg_connections
+                .iter()
+                .map(|pg| pg.set_password(&status.username, &password)),
+        )
+        .await?;
+
+        let expires_at = expires_at.to_rfc3339();
+        let payload = json!({
+            "status": { "password": password, "credentialsExpireAt": expires_at }
+        });
+        self.patch_status(state, payload).await?;
+
+        status.password = password;
+        status.credentials_expire_at = Some(expires_at);
+        Ok(())
+    }
+
+    async fn patch_status(&self, state: &State, payload: serde_json::Value) -> Result<(), Error> {
+        let crds: Api<DbSyncPort> =
+            Api::namespaced(state.kube_client.clone(), &self.namespace().unwrap());
+        crds.patch_status(
+            &self.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(payload),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn publish_rotated(&self, state: &State, note: String) {
+        events::publish(
+            state,
+            self,
+            EventType::Normal,
+            events::CREDENTIALS_ROTATED,
+            "Reconcile",
+            note,
+        )
+        .await;
+    }
+}
+
+// Applies a new username or password to an existing login role and describes the change, or
+// returns None when the credentials didn't change.
+async fn change_credentials<K>(
+    state: &State,
+    resource: &K,
+    group: &str,
+    (username, password): (&str, &str),
+    (new_username, new_password): (&str, &str),
+    pg_connections: &[Postgres],
+) -> Result<Option<String>, Error>
+where
+    K: Resource<DynamicType = ()>,
+{
+    if new_username != username {
+        // The new username may come from a Secret or the status of another version, the role is
+        // only renamed to one that the schema would accept and that isn't a superuser
+        let rejected = match check_username(new_username) {
+            Err(message) => Some(message),
+            Ok(()) if is_superuser(new_username, pg_connections).await? => {
+                Some(format!("the username {new_username} is a superuser"))
+            }
+            Ok(()) => None,
+        };
+        if let Some(message) = rejected {
+            reject_username(state, resource, &message).await;
+            return Err(Error::ConfigError(message));
+        }
+
+        rename_user(
+            state,
+            resource,
+            group,
+            username,
+            new_username,
+            new_password,
+            pg_connections,
+        )
+        .await?;
+        return Ok(Some(format!("user {username} renamed to {new_username}")));
+    }
+
+    if new_password != password {
+        future::try_join_all(
+            pg_connections
+                .iter()
+                .map(|pg| pg.set_password(username, new_password)),
+        )
+        .await?;
+        return Ok(Some(format!("password of user {username} changed")));
+    }
+
+    Ok(None)
+}
+
+async fn reject_username<K>(state: &State, resource: &K, message: &str)
+where
+    K: Resource<DynamicType = ()>,
+{
+    events::publish(
+        state,
+        resource,
+        EventType::Warning,
+        events::USERNAME_REJECTED,
+        "Reconcile",
+        message.to_string(),
+    )
+    .await;
+}
+
+#[derive(Debug, PartialEq)]
+enum RenameStep {
+    RenameOld,
+    DropOld,
+    Refuse,
+    Skip,
+}
+
+// An instance that has both roles only drops the old one when the new one is already in the group
+// of the port, i.e. a rename of this port that didn't finish. Any other existing role belongs to
+// someone else and is never taken over.
+fn rename_step(old_exists: bool, new_exists: bool, new_in_group: bool) -> RenameStep {
+    match (old_exists, new_exists, new_in_group) {
+        (true, false, _) => RenameStep::RenameOld,
+        (_, true, true) if old_exists => RenameStep::DropOld,
+        (_, true, false) => RenameStep::Refuse,
+        _ => RenameStep::Skip,
+    }
+}
+
+// The role is renamed where it exists, so the sessions already open keep working
+async fn rename_user<K>(
+    state: &State,
+    resource: &K,
+    group: &str,
+    username: &str,
+    new_username: &str,
+    password: &str,
+    pg_connections: &[Postgres],
+) -> Result<(), Error>
+where
+    K: Resource<DynamicType = ()>,
+{
+    // Every instance is checked before any role changes, so a refused rename changes nothing
+    let mut steps = Vec::new();
+    for pg in pg_connections {
+        let new_exists = pg.user_exist(new_username).await?;
+        let step = rename_step(
+            pg.user_exist(username).await?,
+            new_exists,
+            new_exists && pg.is_member(new_username, group).await?,
+        );
+        if step == RenameStep::Refuse {
+            let message = format!(
+                "the role {new_username} already exists on {} and isn't one of the port",
+                pg.instance
+            );
+            reject_username(state, resource, &message).await;
+            return Err(Error::ConfigError(message));
+        }
+        steps.push((pg, step));
+    }
+
+    for (pg, step) in steps {
+        match step {
+            RenameStep::RenameOld => pg.rename_user(username, new_username, password).await?,
+            RenameStep::DropOld => {
+                terminate_sessions(state, resource, username, std::slice::from_ref(pg)).await?;
+                pg.drop_user(username).await?;
+                pg.set_password(new_username, password).await?;
+            }
+            RenameStep::Refuse | RenameStep::Skip => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ttl() {
+        assert_eq!(parse_ttl("12h").unwrap(), Duration::hours(12));
+        assert_eq!(parse_ttl("30d").unwrap(), Duration::days(30));
+        assert!(parse_ttl("30m").is_err());
+        assert!(parse_ttl("d").is_err());
+        assert!(parse_ttl("").is_err());
+    }
+
+    #[test]
+    fn test_renewal_time() {
+        let expires_at = DateTime::parse_from_rfc3339("2026-01-31T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let renew_at = renewal_time(expires_at, Duration::days(30));
+        assert_eq!(renew_at.to_rfc3339(), "2026-01-21T00:00:00+00:00");
+    }
+
+    #[test]
+    fn test_check_password() {
+        assert!(check_password("password".into(), "password").is_ok());
+        let err = check_password("pass".into(), "the password in secret creds").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Config Error: the password in secret creds must have at least 8 characters"
+        );
+    }
+
+    #[test]
+    fn test_rename_step() {
+        assert_eq!(rename_step(true, false, false), RenameStep::RenameOld);
+        assert_eq!(rename_step(true, true, true), RenameStep::DropOld);
+        assert_eq!(rename_step(false, true, true), RenameStep::Skip);
+        assert_eq!(rename_step(false, false, false), RenameStep::Skip);
+
+        // The role of another port, an internal service or postgres is never taken over
+        assert_eq!(rename_step(true, true, false), RenameStep::Refuse);
+        assert_eq!(rename_step(false, true, false), RenameStep::Refuse);
+    }
+
+    #[test]
+    fn test_valid_username() {
+        assert!(is_valid_username("dmtr_dbsync1"));
+        assert!(is_valid_username("_app"));
+        assert!(!is_valid_username(""));
+        assert!(!is_valid_username("1app"));
+        assert!(!is_valid_username("App"));
+        assert!(!is_valid_username(r#"app"; drop role postgres; --"#));
+        assert!(!is_valid_username(&"a".repeat(64)));
+    }
+}
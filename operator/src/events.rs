@@ -0,0 +1,138 @@
+use kube::{
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Resource,
+};
+use tracing::warn;
+
+use crate::{get_config, postgres::Provision, Error, State};
+
+// Event reasons are part of the public contract, the UI keys off them.
+pub const USER_PROVISIONED: &str = "UserProvisioned";
+pub const GRANT_DRIFT_FIXED: &str = "GrantDriftFixed";
+pub const CREDENTIALS_ROTATED: &str = "CredentialsRotated";
+pub const DELETION_BLOCKED: &str = "DeletionBlocked";
+pub const UNKNOWN_NETWORK: &str = "UnknownNetwork";
+pub const POSTGRES_UNREACHABLE: &str = "PostgresUnreachable";
+pub const NETWORK_MIGRATED: &str = "NetworkMigrated";
+pub const CREDENTIALS_RENEWED: &str = "CredentialsRenewed";
+pub const CREDENTIAL_EXPIRING: &str = "CredentialExpiring";
+pub const QUOTA_EXCEEDED: &str = "QuotaExceeded";
+pub const NETWORK_UNAVAILABLE: &str = "NetworkUnavailable";
+pub const USERNAME_REJECTED: &str = "UsernameRejected";
+
+pub async fn publish<K>(
+    state: &State,
+    resource: &K,
+    type_: EventType,
+    reason: &str,
+    action: &str,
+    note: String,
+) where
+    K: Resource<DynamicType = ()>,
+{
+    let reporter = Reporter {
+        controller: "ext-cardano-dbsync".into(),
+        instance: Some(get_config().lease_identity.clone()),
+    };
+    let recorder = Recorder::new(
+        state.kube_client.clone(),
+        reporter,
+        resource.object_ref(&()),
+    );
+
+    let event = Event {
+        type_,
+        reason: reason.into(),
+        note: Some(note),
+        action: action.into(),
+        secondary: None,
+    };
+
+    if let Err(err) = recorder.publish(event).await {
+        warn!(error = err.to_string(), reason, "error to publish event");
+    }
+}
+
+// The event of provisioning a user on one instance, none when nothing changed. Errors other than an
+// unreachable instance are only logged.
+pub fn provision_event(
+    result: &Result<Provision, Error>,
+    username: &str,
+    instance: &str,
+) -> Option<(EventType, &'static str, String)> {
+    match result {
+        Ok(Provision::Created) => Some((
+            EventType::Normal,
+            USER_PROVISIONED,
+            format!("user {username} provisioned on {instance}"),
+        )),
+        Ok(Provision::DriftFixed) => Some((
+            EventType::Normal,
+            GRANT_DRIFT_FIXED,
+            format!("grants of user {username} restored on {instance}"),
+        )),
+        Ok(Provision::Unchanged) => None,
+        Err(Error::PgUnreachable(_)) => Some((
+            EventType::Warning,
+            POSTGRES_UNREACHABLE,
+            format!("postgres {instance} is unreachable"),
+        )),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REASONS: [&str; 11] = [
+        USER_PROVISIONED,
+        GRANT_DRIFT_FIXED,
+        CREDENTIALS_ROTATED,
+        DELETION_BLOCKED,
+        UNKNOWN_NETWORK,
+        POSTGRES_UNREACHABLE,
+        NETWORK_MIGRATED,
+        CREDENTIALS_RENEWED,
+        CREDENTIAL_EXPIRING,
+        QUOTA_EXCEEDED,
+        NETWORK_UNAVAILABLE,
+    ];
+
+    #[test]
+    fn test_reasons_documented() {
+        let readme = include_str!("../README.md");
+        for reason in REASONS {
+            assert!(
+                readme.contains(&format!("| {reason} ")),
+                "{reason} is missing from the README"
+            );
+            assert!(reason.chars().all(|c| c.is_ascii_alphabetic()));
+        }
+    }
+
+    #[test]
+    fn test_provision_event() {
+        let (type_, reason, note) =
+            provision_event(&Ok(Provision::Created), "dmtr_dbsync1", "pg-0").unwrap();
+        assert_eq!(type_, EventType::Normal);
+        assert_eq!(reason, USER_PROVISIONED);
+        assert_eq!(note, "user dmtr_dbsync1 provisioned on pg-0");
+
+        let (type_, reason, _) =
+            provision_event(&Ok(Provision::DriftFixed), "dmtr_dbsync1", "pg-0").unwrap();
+        assert_eq!(type_, EventType::Normal);
+        assert_eq!(reason, GRANT_DRIFT_FIXED);
+
+        assert!(provision_event(&Ok(Provision::Unchanged), "dmtr_dbsync1", "pg-0").is_none());
+
+        let unreachable = Err(Error::PgUnreachable("timeout".into()));
+        let (type_, reason, note) = provision_event(&unreachable, "dmtr_dbsync1", "pg-0").unwrap();
+        assert_eq!(type_, EventType::Warning);
+        assert_eq!(reason, POSTGRES_UNREACHABLE);
+        assert_eq!(note, "postgres pg-0 is unreachable");
+
+        let failed = Err(Error::PgError("permission denied".into()));
+        assert!(provision_event(&failed, "dmtr_dbsync1", "pg-0").is_none());
+    }
+}
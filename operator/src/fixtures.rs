@@ -0,0 +1,26 @@
+// Ports for the tests of the library and of the admin CLI, which includes this file by path
+use super::DbSyncPort;
+
+pub fn port(name: &str, network: &str) -> DbSyncPort {
+    serde_json::from_value(serde_json::json!({
+        "apiVersion": "demeter.run/v1beta1",
+        "kind": "DbSyncPort",
+        "metadata": { "name": name, "namespace": "prj-test" },
+        "spec": { "network": network }
+    }))
+    .unwrap()
+}
+
+// The first username is the primary credential and the second one, if any, the secondary
+pub fn provisioned(mut port: DbSyncPort, usernames: &[&str]) -> DbSyncPort {
+    let mut status = serde_json::json!({
+        "username": usernames[0],
+        "password": "password",
+        "group": format!("{}_group", usernames[0])
+    });
+    if let Some(secondary) = usernames.get(1) {
+        status["secondary"] = serde_json::json!({ "username": secondary, "password": "password" });
+    }
+    port.status = Some(serde_json::from_value(status).unwrap());
+    port
+}
@@ -0,0 +1,259 @@
+use chrono::Utc;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+use crate::{get_config, State};
+
+pub const CONTROLLER_LOOP: &str = "controller";
+pub const COLLECTOR_LOOP: &str = "metrics_collector";
+pub const SCHEMA_LOOP: &str = "schema_bootstrap";
+
+#[derive(Default)]
+pub struct LoopHealth {
+    running: AtomicBool,
+    started: AtomicBool,
+    in_flight: AtomicUsize,
+    // Since when a tick is in flight, and when the last one succeeded
+    busy_since: AtomicI64,
+    last_tick: AtomicI64,
+}
+
+impl LoopHealth {
+    fn touch(&self) {
+        self.last_tick
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    fn is_stalled(&self, idle_is_healthy: bool, threshold: Duration) -> bool {
+        if !self.running.load(Ordering::Relaxed) {
+            return true;
+        }
+        let since = match idle_is_healthy {
+            true if self.in_flight.load(Ordering::Relaxed) == 0 => return false,
+            true => self.busy_since.load(Ordering::Relaxed),
+            false => self.last_tick.load(Ordering::Relaxed),
+        };
+
+        let elapsed = Utc::now().timestamp() - since;
+        elapsed > threshold.as_secs() as i64
+    }
+}
+
+// The controller is event driven, so it is only considered stalled when a reconcile is in flight
+// for longer than the threshold. The collector ticks on a fixed delay and its last successful
+// tick must always be recent. Index builds of the schema bootstrap run for hours, so it's only
+// stalled once it stopped.
+pub struct Health {
+    loops: HashMap<&'static str, LoopHealth>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        let loops = [CONTROLLER_LOOP, COLLECTOR_LOOP, SCHEMA_LOOP]
+            .into_iter()
+            .map(|name| (name, LoopHealth::default()))
+            .collect();
+
+        Self { loops }
+    }
+}
+
+impl Health {
+    fn get(&self, name: &str) -> &LoopHealth {
+        self.loops.get(name).expect("loop is not registered")
+    }
+
+    pub fn loop_started(&self, name: &str) {
+        let health = self.get(name);
+        health.running.store(true, Ordering::Relaxed);
+        health.in_flight.store(0, Ordering::Relaxed);
+        health.touch();
+    }
+
+    pub fn loop_stopped(&self, name: &str) {
+        self.get(name).running.store(false, Ordering::Relaxed);
+    }
+
+    pub fn tick_started(&self, name: &str) {
+        let health = self.get(name);
+        if health.in_flight.fetch_add(1, Ordering::Relaxed) == 0 {
+            health
+                .busy_since
+                .store(Utc::now().timestamp(), Ordering::Relaxed);
+        }
+    }
+
+    // A loop that fails on every tick isn't making progress, so only a successful tick counts
+    pub fn tick_finished(&self, name: &str, succeeded: bool) {
+        let health = self.get(name);
+        let _ = health
+            .in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1));
+        if succeeded {
+            health.started.store(true, Ordering::Relaxed);
+            health.touch();
+        }
+    }
+
+    pub fn stalled_loops(&self) -> Vec<&'static str> {
+        let threshold = get_config().loop_stall_threshold;
+
+        let mut stalled: Vec<&'static str> = self
+            .loops
+            .iter()
+            .filter(|(name, health)| match **name {
+                SCHEMA_LOOP => !health.running.load(Ordering::Relaxed),
+                name => health.is_stalled(name == CONTROLLER_LOOP, threshold),
+            })
+            .map(|(name, _)| *name)
+            .collect();
+        stalled.sort();
+        stalled
+    }
+
+    pub fn pending_loops(&self) -> Vec<&'static str> {
+        let mut pending: Vec<&'static str> = self
+            .loops
+            .iter()
+            .filter(|(name, health)| {
+                **name == COLLECTOR_LOOP && !health.started.load(Ordering::Relaxed)
+            })
+            .map(|(name, _)| *name)
+            .collect();
+        pending.sort();
+        pending
+    }
+}
+
+pub async fn supervise<F, Fut>(name: &'static str, state: Arc<State>, run: F)
+where
+    F: Fn(Arc<State>) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut backoff = Duration::from_secs(1);
+
+    loop {
+        state.health.loop_started(name);
+        let result = tokio::spawn(run(state.clone())).await;
+        state.health.loop_stopped(name);
+
+        match result {
+            Ok(()) => {
+                info!(name, "loop finished");
+                return;
+            }
+            Err(err) if err.is_panic() && state.shutdown.is_triggered() => {
+                error!(
+                    name,
+                    error = err.to_string(),
+                    "loop panicked during shutdown"
+                );
+                return;
+            }
+            Err(err) if err.is_panic() => {
+                error!(name, error = err.to_string(), "loop panicked, restarting");
+                state.metrics.count_loop_restart(name);
+            }
+            Err(err) => {
+                warn!(name, error = err.to_string(), "loop cancelled");
+                return;
+            }
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(60));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: Duration = Duration::from_secs(300);
+
+    fn stale(health: &Health, name: &str) {
+        let stale = Utc::now().timestamp() - THRESHOLD.as_secs() as i64 - 1;
+        health.get(name).last_tick.store(stale, Ordering::Relaxed);
+        health.get(name).busy_since.store(stale, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_is_stalled() {
+        let health = Health::default();
+        let collector = health.get(COLLECTOR_LOOP);
+        assert!(collector.is_stalled(false, THRESHOLD));
+
+        health.loop_started(COLLECTOR_LOOP);
+        assert!(!collector.is_stalled(false, THRESHOLD));
+
+        // Failed ticks don't count as progress
+        stale(&health, COLLECTOR_LOOP);
+        health.tick_started(COLLECTOR_LOOP);
+        health.tick_finished(COLLECTOR_LOOP, false);
+        assert!(collector.is_stalled(false, THRESHOLD));
+
+        health.tick_started(COLLECTOR_LOOP);
+        health.tick_finished(COLLECTOR_LOOP, true);
+        assert!(!collector.is_stalled(false, THRESHOLD));
+
+        health.loop_stopped(COLLECTOR_LOOP);
+        assert!(collector.is_stalled(false, THRESHOLD));
+    }
+
+    #[test]
+    fn test_controller_idle() {
+        let health = Health::default();
+        let controller = health.get(CONTROLLER_LOOP);
+        health.loop_started(CONTROLLER_LOOP);
+
+        // An idle controller is healthy however old its last tick is
+        stale(&health, CONTROLLER_LOOP);
+        assert!(!controller.is_stalled(true, THRESHOLD));
+
+        health.tick_started(CONTROLLER_LOOP);
+        stale(&health, CONTROLLER_LOOP);
+        assert!(controller.is_stalled(true, THRESHOLD));
+
+        health.tick_finished(CONTROLLER_LOOP, false);
+        assert!(!controller.is_stalled(true, THRESHOLD));
+    }
+
+    #[test]
+    fn test_schema_loop() {
+        let health = Health::default();
+        health.loop_started(CONTROLLER_LOOP);
+        health.loop_started(COLLECTOR_LOOP);
+        assert_eq!(health.stalled_loops(), vec![SCHEMA_LOOP]);
+
+        // Only a stopped schema bootstrap is stalled, whatever its last tick
+        health.loop_started(SCHEMA_LOOP);
+        stale(&health, SCHEMA_LOOP);
+        assert!(health.stalled_loops().is_empty());
+
+        health.loop_stopped(SCHEMA_LOOP);
+        assert_eq!(health.stalled_loops(), vec![SCHEMA_LOOP]);
+    }
+
+    #[test]
+    fn test_pending_loops() {
+        let health = Health::default();
+        assert_eq!(health.pending_loops(), vec![COLLECTOR_LOOP]);
+
+        health.loop_started(COLLECTOR_LOOP);
+        health.tick_started(COLLECTOR_LOOP);
+        health.tick_finished(COLLECTOR_LOOP, false);
+        assert_eq!(health.pending_loops(), vec![COLLECTOR_LOOP]);
+
+        health.tick_started(COLLECTOR_LOOP);
+        health.tick_finished(COLLECTOR_LOOP, true);
+        assert!(health.pending_loops().is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+use chrono::Utc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::{info, instrument, warn};
+
+use crate::{get_config, postgres::Postgres, Error, State};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceState {
+    Healthy,
+    Degraded,
+    Excluded,
+}
+
+impl InstanceState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InstanceState::Healthy => "healthy",
+            InstanceState::Degraded => "degraded",
+            InstanceState::Excluded => "excluded",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    Ok { lag: Option<f64> },
+    ReadOnly,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    state: InstanceState,
+    failures: u32,
+}
+
+// Health of each database, keyed by network and instance. Instances are healthy until the first
+// check, so a restart doesn't hold back the reconciles.
+#[derive(Default)]
+pub struct Instances {
+    entries: RwLock<HashMap<(String, String), Entry>>,
+}
+
+impl Instances {
+    pub fn state(&self, network: &str, instance: &str) -> InstanceState {
+        self.entries
+            .read()
+            .unwrap()
+            .get(&(network.to_string(), instance.to_string()))
+            .map(|entry| entry.state)
+            .unwrap_or(InstanceState::Healthy)
+    }
+
+    // Splits the connections of a network into the ones to provision and the excluded instances
+    pub fn available(
+        &self,
+        network: &str,
+        connections: &[Postgres],
+    ) -> (Vec<Postgres>, Vec<String>) {
+        let mut available = Vec::new();
+        let mut excluded = Vec::new();
+        for pg in connections {
+            match self.state(network, &pg.instance) {
+                InstanceState::Excluded => excluded.push(pg.instance.clone()),
+                _ => available.push(pg.clone()),
+            }
+        }
+        (available, excluded)
+    }
+
+    pub fn record(&self, network: &str, instance: &str, probe: &Probe) -> (InstanceState, bool) {
+        let config = get_config();
+        let key = (network.to_string(), instance.to_string());
+
+        let mut entries = self.entries.write().unwrap();
+        let entry = entries.entry(key).or_insert(Entry {
+            state: InstanceState::Healthy,
+            failures: 0,
+        });
+
+        let previous = entry.state;
+        next_state(
+            entry,
+            probe,
+            config.instance_failure_threshold,
+            config.sync_lag_threshold.as_secs_f64(),
+        );
+        (entry.state, entry.state != previous)
+    }
+}
+
+// A failed probe degrades the instance, it's only excluded after a number of failures in a row.
+// Read-only instances can't take new roles, so they are excluded at once.
+fn next_state(entry: &mut Entry, probe: &Probe, failure_threshold: u32, lag_threshold: f64) {
+    entry.state = match probe {
+        Probe::Ok { lag } => {
+            entry.failures = 0;
+            match lag {
+                Some(lag) if *lag <= lag_threshold => InstanceState::Healthy,
+                _ => InstanceState::Degraded,
+            }
+        }
+        Probe::ReadOnly => {
+            entry.failures = 0;
+            InstanceState::Excluded
+        }
+        Probe::Failed(_) => {
+            entry.failures += 1;
+            match entry.failures >= failure_threshold {
+                true => InstanceState::Excluded,
+                false => InstanceState::Degraded,
+            }
+        }
+    };
+}
+
+async fn probe(pg: &Postgres) -> Probe {
+    let result = tokio::time::timeout(PROBE_TIMEOUT, async {
+        if pg.read_only().await? {
+            return Ok(Probe::ReadOnly);
+        }
+        let lag = pg
+            .tip()
+            .await?
+            .map(|(_, time)| (Utc::now().timestamp() as f64 - time).max(0.));
+        Ok::<_, Error>(Probe::Ok { lag })
+    })
+    .await;
+
+    match result {
+        Ok(Ok(probe)) => probe,
+        Ok(Err(err)) => Probe::Failed(err.to_string()),
+        Err(_) => Probe::Failed("probe timed out".into()),
+    }
+}
+
+// Runs on every replica, so a new leader already knows which instances to skip
+#[instrument("instance checks run", skip_all)]
+pub async fn run_instance_checks(state: Arc<State>) {
+    let config = get_config();
+    if config.instance_check_interval.is_zero() {
+        return;
+    }
+
+    loop {
+        for (network, connections) in state.pg_connections().iter() {
+            let probes = futures::future::join_all(connections.iter().map(probe)).await;
+
+            for (pg, probe) in connections.iter().zip(probes) {
+                let (instance_state, changed) =
+                    state.instances.record(network, &pg.instance, &probe);
+                state
+                    .metrics
+                    .instance_state(network, &pg.instance, instance_state);
+
+                if !changed {
+                    continue;
+                }
+                match &probe {
+                    Probe::Failed(error) => warn!(
+                        network,
+                        instance = pg.instance,
+                        error,
+                        state = instance_state.as_str(),
+                        "instance check failed"
+                    ),
+                    _ => info!(
+                        network,
+                        instance = pg.instance,
+                        state = instance_state.as_str(),
+                        "instance state changed"
+                    ),
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(config.instance_check_interval) => {},
+            _ = state.shutdown.wait() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_state() {
+        let mut entry = Entry {
+            state: InstanceState::Healthy,
+            failures: 0,
+        };
+        let failed = Probe::Failed("connection refused".into());
+
+        next_state(&mut entry, &failed, 3, 600.);
+        assert_eq!(entry.state, InstanceState::Degraded);
+        next_state(&mut entry, &failed, 3, 600.);
+        next_state(&mut entry, &failed, 3, 600.);
+        assert_eq!(entry.state, InstanceState::Excluded);
+
+        next_state(&mut entry, &Probe::Ok { lag: Some(30.) }, 3, 600.);
+        assert_eq!(entry.state, InstanceState::Healthy);
+        assert_eq!(entry.failures, 0);
+
+        next_state(&mut entry, &Probe::Ok { lag: Some(900.) }, 3, 600.);
+        assert_eq!(entry.state, InstanceState::Degraded);
+        next_state(&mut entry, &Probe::Ok { lag: None }, 3, 600.);
+        assert_eq!(entry.state, InstanceState::Degraded);
+
+        next_state(&mut entry, &Probe::ReadOnly, 3, 600.);
+        assert_eq!(entry.state, InstanceState::Excluded);
+    }
+}
@@ -0,0 +1,342 @@
+use futures::{future, StreamExt};
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::{
+        controller::Action,
+        events::EventType,
+        finalizer::{finalizer, Event},
+        watcher::Config as WatcherConfig,
+        Controller,
+    },
+    Api, CustomResource, ResourceExt,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    controller::{
+        is_superuser, is_system_role, is_valid_username, provision_user, resync_action,
+        terminate_sessions, RECONCILE_AT_ANNOTATION, USERNAME_PATTERN,
+    },
+    events, get_config,
+    health::CONTROLLER_LOOP,
+    postgres::{Access, Grants, Postgres},
+    Error, State,
+};
+
+pub static INTERNAL_DB_USER_FINALIZER: &str = "internaldbusers.demeter.run";
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "InternalDbUser",
+    group = "demeter.run",
+    version = "v1alpha1",
+    shortname = "idbu"
+)]
+#[kube(status = "InternalDbUserStatus")]
+#[kube(printcolumn = r#"
+        {"name": "Username", "jsonPath": ".status.username", "type": "string"},
+        {"name": "Access", "jsonPath": ".spec.access", "type": "string"},
+        {"name": "Networks", "jsonPath": ".status.networks", "type": "string"}
+    "#)]
+#[serde(rename_all = "camelCase")]
+pub struct InternalDbUserSpec {
+    pub username: String,
+    pub password: Option<String>,
+    pub networks: Vec<String>,
+    #[serde(default)]
+    pub access: InternalDbUserAccess,
+    /// Statement timeout in milliseconds, defaults to STATEMENT_TIMEOUT
+    pub statement_timeout: Option<u64>,
+}
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum InternalDbUserAccess {
+    #[default]
+    Read,
+    Write,
+}
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct InternalDbUserStatus {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub networks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconcile_at: Option<String>,
+}
+
+impl InternalDbUser {
+    fn grants(&self) -> Grants {
+        let access = match self.spec.access {
+            InternalDbUserAccess::Read => Access::Read,
+            InternalDbUserAccess::Write => Access::Write,
+        };
+        let statement_timeout = self
+            .spec
+            .statement_timeout
+            .unwrap_or(get_config().statement_timeout);
+
+        Grants {
+            access,
+            statement_timeout,
+        }
+    }
+
+    // Aliases are replaced by the canonical name, so a network listed twice is provisioned once
+    fn networks(&self, state: &State) -> Vec<String> {
+        let mut networks: Vec<String> = self
+            .spec
+            .networks
+            .iter()
+            .map(|network| state.networks.canonical(network))
+            .collect();
+        networks.sort();
+        networks.dedup();
+        networks
+    }
+
+    async fn pg_connections(&self, state: &State, network: &str) -> Result<Vec<Postgres>, Error> {
+        match state.get_pg_by_network(network) {
+            Ok(connections) => Ok(connections),
+            Err(err) => {
+                let note = format!("network {network} is not supported");
+                events::publish(
+                    state,
+                    self,
+                    EventType::Warning,
+                    events::UNKNOWN_NETWORK,
+                    "Reconcile",
+                    note,
+                )
+                .await;
+                Err(err)
+            }
+        }
+    }
+
+    // The role is shared by every database of the instances, so it can't be one of postgres or
+    // of a port
+    async fn rejected_username(
+        &self,
+        state: &State,
+        username: &str,
+        pg_connections: &[Postgres],
+    ) -> Result<Option<String>, Error> {
+        if !is_valid_username(username) {
+            return Ok(Some(format!(
+                "the username {username} must match {USERNAME_PATTERN}"
+            )));
+        }
+        if is_system_role(username) || username.starts_with(&get_config().username_prefix) {
+            return Ok(Some(format!("the username {username} is reserved")));
+        }
+
+        let ports = state.ports.list(state).await?;
+        let port = ports.iter().find(|port| {
+            port.status.as_ref().is_some_and(|status| {
+                status.usernames().contains(&username) || port.group(status) == username
+            })
+        });
+        if let Some(port) = port {
+            return Ok(Some(format!(
+                "the username {username} is used by the port {}/{}",
+                port.namespace().unwrap_or_default(),
+                port.name_any()
+            )));
+        }
+
+        if is_superuser(username, pg_connections).await? {
+            return Ok(Some(format!("the username {username} is a superuser")));
+        }
+        Ok(None)
+    }
+
+    async fn reconcile(&self, state: Arc<State>) -> Result<Action, Error> {
+        let crds: Api<InternalDbUser> = Api::all(state.kube_client.clone());
+        let name = self.name_any();
+
+        let status = self.status.clone().unwrap_or(InternalDbUserStatus {
+            username: self.spec.username.clone(),
+            password: self
+                .spec
+                .password
+                .clone()
+                .unwrap_or(Alphanumeric.sample_string(&mut rand::thread_rng(), 16)),
+            networks: Vec::new(),
+            reconcile_at: None,
+        });
+
+        let networks = self.networks(&state);
+        let mut pg_connections: Vec<Postgres> = Vec::new();
+        let mut excluded: Vec<String> = Vec::new();
+        for network in networks.iter() {
+            let connections = self.pg_connections(&state, network).await?;
+            let (available, network_excluded) = state.instances.available(network, &connections);
+            pg_connections.extend(available);
+            excluded.extend(network_excluded);
+        }
+
+        if let Some(note) = self
+            .rejected_username(&state, &status.username, &pg_connections)
+            .await?
+        {
+            events::publish(
+                &state,
+                self,
+                EventType::Warning,
+                events::USERNAME_REJECTED,
+                "Reconcile",
+                note.clone(),
+            )
+            .await;
+            return Err(Error::ConfigError(note));
+        }
+
+        if self.status.is_none() {
+            let payload = json!({ "status": status });
+            crds.patch_status(&name, &PatchParams::default(), &Patch::Merge(payload))
+                .await?;
+            info!({ status.username }, "internal user created");
+        }
+
+        let reconcile_at = self.annotations().get(RECONCILE_AT_ANNOTATION).cloned();
+        let forced = reconcile_at.is_some() && reconcile_at != status.reconcile_at;
+
+        // Dropping what the role owns on a removed network also revokes its privileges on the
+        // other databases of the instance, so the grants of the remaining networks are applied
+        // again after it.
+        let removed: Vec<String> = status
+            .networks
+            .iter()
+            .filter(|network| !networks.contains(network))
+            .cloned()
+            .collect();
+        for network in removed.iter() {
+            let Ok(connections) = state.get_pg_by_network(network) else {
+                continue;
+            };
+            future::try_join_all(connections.iter().map(|pg| pg.drop_owned(&status.username)))
+                .await?;
+            info!(network, "internal user access revoked");
+        }
+
+        provision_user(
+            &state,
+            self,
+            &status.username,
+            &status.password,
+            &self.grants(),
+            &pg_connections,
+            forced || !removed.is_empty(),
+        )
+        .await?;
+
+        if networks != status.networks || forced {
+            let payload = json!({
+                "status": { "networks": networks, "reconcileAt": reconcile_at }
+            });
+            crds.patch_status(&name, &PatchParams::default(), &Patch::Merge(payload))
+                .await?;
+        }
+
+        if !excluded.is_empty() {
+            return Ok(Action::requeue(get_config().instance_check_interval));
+        }
+        Ok(resync_action())
+    }
+
+    async fn cleanup(&self, state: Arc<State>) -> Result<Action, Error> {
+        if let Some(status) = &self.status {
+            let username = status.username.clone();
+
+            // Excluded instances can't be reached, the role is left on them instead of blocking the
+            // deletion and has to be dropped by hand once they recover
+            let mut pg_connections: Vec<Postgres> = Vec::new();
+            let mut excluded: Vec<String> = Vec::new();
+            for network in status.networks.iter() {
+                let Ok(connections) = state.get_pg_by_network(network) else {
+                    continue;
+                };
+                let (available, network_excluded) =
+                    state.instances.available(network, &connections);
+                pg_connections.extend(available);
+                excluded.extend(network_excluded);
+            }
+            if !excluded.is_empty() {
+                warn!(
+                    username,
+                    excluded = excluded.join(", "),
+                    "roles left on excluded instances"
+                );
+            }
+
+            terminate_sessions(&state, self, &username, &pg_connections).await?;
+
+            // Privileges are per database, they are dropped everywhere before the role itself
+            future::try_join_all(pg_connections.iter().map(|pg| pg.drop_owned(&username))).await?;
+            for pg in pg_connections.iter() {
+                pg.drop_user(&username).await?;
+            }
+
+            info!({ username }, "internal user dropped");
+        }
+
+        Ok(Action::await_change())
+    }
+}
+
+#[instrument(
+    "reconcile",
+    skip_all,
+    fields(
+        name = crd.name_any(),
+        generation = crd.metadata.generation.unwrap_or_default(),
+    )
+)]
+async fn reconcile(crd: Arc<InternalDbUser>, state: Arc<State>) -> Result<Action, Error> {
+    let started = Instant::now();
+    state.health.tick_started(CONTROLLER_LOOP);
+
+    let crds: Api<InternalDbUser> = Api::all(state.kube_client.clone());
+    let result = finalizer(&crds, INTERNAL_DB_USER_FINALIZER, crd, |event| async {
+        match event {
+            Event::Apply(crd) => crd.reconcile(state.clone()).await,
+            Event::Cleanup(crd) => crd.cleanup(state.clone()).await,
+        }
+    })
+    .await
+    .map_err(|e| Error::FinalizerError(Box::new(e)));
+
+    state.health.tick_finished(CONTROLLER_LOOP, result.is_ok());
+    state
+        .metrics
+        .reconcile_finished(started.elapsed(), result.is_ok());
+
+    result
+}
+
+fn error_policy(crd: Arc<InternalDbUser>, error: &Error, state: Arc<State>) -> Action {
+    error!(error = error.to_string(), "reconcile failed");
+    state.metrics.reconcile_failure(crd.as_ref(), "", error);
+    Action::requeue(Duration::from_secs(5))
+}
+
+// Runs until the shutdown or the loss of the leadership, as the DbSyncPort controller does
+pub async fn run_controller(state: Arc<State>, crds: Api<InternalDbUser>) {
+    Controller::new(crds, WatcherConfig::default().any_semantic())
+        .graceful_shutdown_on(state.shutdown.wait())
+        .graceful_shutdown_on(state.leadership.lost())
+        .run(reconcile, error_policy, state.clone())
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()))
+        .await;
+}
@@ -0,0 +1,300 @@
+use chrono::Utc;
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+};
+use kube::{api::PostParams, Api};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use tracing::{error, info, instrument};
+
+use crate::{get_config, Error, State};
+
+pub struct Leadership {
+    tx: watch::Sender<bool>,
+}
+
+impl Default for Leadership {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(false);
+        Self { tx }
+    }
+}
+
+impl Leadership {
+    pub fn is_leader(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    fn set(&self, is_leader: bool) -> bool {
+        self.tx.send_if_modified(|current| {
+            let changed = *current != is_leader;
+            *current = is_leader;
+            changed
+        })
+    }
+
+    pub async fn acquired(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|is_leader| *is_leader).await;
+    }
+
+    pub fn lost(&self) -> impl std::future::Future<Output = ()> + Send + Sync + 'static {
+        let mut rx = self.tx.subscribe();
+        async move {
+            let _ = rx.wait_for(|is_leader| !*is_leader).await;
+        }
+    }
+}
+
+// The leader steps down when it couldn't renew for this part of the lease, well before a follower
+// can take it over, so two replicas never lead at the same time even with some clock skew
+fn renew_deadline(lease_duration: Duration) -> Duration {
+    lease_duration * 2 / 3
+}
+
+// Only the holder of the Lease reconciles and meters usage. Followers keep the pools and the http
+// server running and take over once the holder stops renewing for LEASE_DURATION seconds.
+#[instrument("leader election run", skip_all)]
+pub async fn run_leader_election(state: Arc<State>) {
+    let config = get_config();
+
+    if !config.leader_election {
+        set_leadership(&state, true);
+        return;
+    }
+
+    let namespace = config
+        .lease_namespace
+        .clone()
+        .unwrap_or(state.kube_client.default_namespace().to_string());
+    let api: Api<Lease> = Api::namespaced(state.kube_client.clone(), &namespace);
+
+    info!(
+        lease = config.lease_name,
+        identity = config.lease_identity,
+        "leader election running"
+    );
+
+    let retry_period = config.lease_duration / 3;
+    let deadline = renew_deadline(config.lease_duration);
+    let mut last_renew = Instant::now();
+
+    loop {
+        // The renew time written to the Lease is taken before the request, so is the deadline
+        let attempt = Instant::now();
+        let result = tokio::time::timeout(retry_period, try_acquire_or_renew(&api))
+            .await
+            .unwrap_or_else(|_| Err(Error::KubeError(kube::Error::Service("timeout".into()))));
+
+        match result {
+            Ok(is_leader) => {
+                if is_leader {
+                    last_renew = attempt;
+                }
+                set_leadership(&state, is_leader);
+            }
+            Err(err) => {
+                error!(error = err.to_string(), "error to renew lease");
+                if last_renew.elapsed() > deadline {
+                    set_leadership(&state, false);
+                }
+            }
+        }
+
+        // A leader that missed the deadline steps down between the retries too
+        let sleep = match state.leadership.is_leader() {
+            true => retry_period.min(deadline.saturating_sub(last_renew.elapsed())),
+            false => retry_period,
+        };
+        tokio::time::sleep(sleep).await;
+        if state.leadership.is_leader() && last_renew.elapsed() > deadline {
+            set_leadership(&state, false);
+        }
+    }
+}
+
+// Gives the Lease up on shutdown, once the loops are drained, so a follower takes over right away
+// instead of waiting for it to expire. The election task must be stopped first.
+pub async fn release_lease(state: &State) {
+    let config = get_config();
+    if !config.leader_election || !state.leadership.is_leader() {
+        return;
+    }
+    set_leadership(state, false);
+
+    let namespace = config
+        .lease_namespace
+        .clone()
+        .unwrap_or(state.kube_client.default_namespace().to_string());
+    let api: Api<Lease> = Api::namespaced(state.kube_client.clone(), &namespace);
+
+    let result = async {
+        let Some(mut lease) = api.get_opt(&config.lease_name).await? else {
+            return Ok(());
+        };
+        let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+        if !release(spec, &config.lease_identity) {
+            return Ok(());
+        }
+        api.replace(&config.lease_name, &PostParams::default(), &lease)
+            .await?;
+        info!("lease released");
+        Ok::<(), Error>(())
+    }
+    .await;
+
+    if let Err(err) = result {
+        error!(error = err.to_string(), "error to release lease");
+    }
+}
+
+fn set_leadership(state: &State, is_leader: bool) {
+    if state.leadership.set(is_leader) {
+        info!(is_leader, "leadership changed");
+        state.metrics.leadership_changed(is_leader);
+    }
+}
+
+async fn try_acquire_or_renew(api: &Api<Lease>) -> Result<bool, Error> {
+    let config = get_config();
+    let identity = config.lease_identity.clone();
+    let duration = config.lease_duration.as_secs() as i32;
+    let now = MicroTime(Utc::now());
+
+    let Some(mut lease) = api.get_opt(&config.lease_name).await? else {
+        let lease = Lease {
+            metadata: ObjectMeta {
+                name: Some(config.lease_name.clone()),
+                ..Default::default()
+            },
+            spec: Some(LeaseSpec {
+                holder_identity: Some(identity),
+                lease_duration_seconds: Some(duration),
+                acquire_time: Some(now.clone()),
+                renew_time: Some(now),
+                lease_transitions: Some(0),
+            }),
+        };
+
+        return match api.create(&PostParams::default(), &lease).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+            Err(err) => Err(err.into()),
+        };
+    };
+
+    let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+    if !acquire_or_renew(spec, &identity, duration, now) {
+        return Ok(false);
+    }
+
+    // The replace carries the resourceVersion, so a concurrent takeover fails with a conflict
+    match api
+        .replace(&config.lease_name, &PostParams::default(), &lease)
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Takes the lease when it's held by this replica, expired or released. False when another
+// replica holds it.
+fn acquire_or_renew(spec: &mut LeaseSpec, identity: &str, duration: i32, now: MicroTime) -> bool {
+    let is_holder = spec.holder_identity.as_deref() == Some(identity);
+    let expired = match &spec.renew_time {
+        Some(renew_time) => {
+            let lease_duration = spec.lease_duration_seconds.unwrap_or(duration);
+            renew_time.0 + chrono::Duration::seconds(lease_duration.into()) < now.0
+        }
+        None => true,
+    };
+
+    if !is_holder && !expired {
+        return false;
+    }
+
+    if !is_holder {
+        spec.holder_identity = Some(identity.to_string());
+        spec.acquire_time = Some(now.clone());
+        spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+    }
+    spec.renew_time = Some(now);
+    spec.lease_duration_seconds = Some(duration);
+    true
+}
+
+// False when the lease isn't held by this replica anymore
+fn release(spec: &mut LeaseSpec, identity: &str) -> bool {
+    if spec.holder_identity.as_deref() != Some(identity) {
+        return false;
+    }
+    spec.holder_identity = None;
+    spec.renew_time = None;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: i64) -> MicroTime {
+        MicroTime(chrono::DateTime::from_timestamp(seconds, 0).unwrap())
+    }
+
+    fn held_by(identity: &str, renewed_at: i64) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(identity.into()),
+            lease_duration_seconds: Some(15),
+            acquire_time: Some(at(0)),
+            renew_time: Some(at(renewed_at)),
+            lease_transitions: Some(1),
+        }
+    }
+
+    #[test]
+    fn test_acquire_or_renew() {
+        // Held by another replica until the lease expires
+        let mut spec = held_by("other", 100);
+        assert!(!acquire_or_renew(&mut spec, "me", 15, at(115)));
+        assert_eq!(spec.holder_identity.as_deref(), Some("other"));
+
+        assert!(acquire_or_renew(&mut spec, "me", 15, at(116)));
+        assert_eq!(spec.holder_identity.as_deref(), Some("me"));
+        assert_eq!(spec.lease_transitions, Some(2));
+        assert_eq!(spec.acquire_time, Some(at(116)));
+
+        // The holder renews without a transition
+        assert!(acquire_or_renew(&mut spec, "me", 15, at(120)));
+        assert_eq!(spec.renew_time, Some(at(120)));
+        assert_eq!(spec.acquire_time, Some(at(116)));
+        assert_eq!(spec.lease_transitions, Some(2));
+    }
+
+    #[test]
+    fn test_release() {
+        let mut spec = held_by("other", 100);
+        assert!(!release(&mut spec, "me"));
+
+        let mut spec = held_by("me", 100);
+        assert!(release(&mut spec, "me"));
+        // A released lease is taken over right away
+        assert!(acquire_or_renew(&mut spec, "other", 15, at(101)));
+        assert_eq!(spec.holder_identity.as_deref(), Some("other"));
+    }
+
+    #[test]
+    fn test_renew_deadline() {
+        let lease_duration = Duration::from_secs(15);
+        let deadline = renew_deadline(lease_duration);
+        assert_eq!(deadline, Duration::from_secs(10));
+        // A renewal attempt is bounded by the retry period, so the leader steps down before
+        // the lease expires
+        assert!(deadline + lease_duration / 3 <= lease_duration);
+    }
+}
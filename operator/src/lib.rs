@@ -1,12 +1,19 @@
-use kube::Client;
+use health::Health;
+use instances::Instances;
+use kube::{runtime::finalizer, Client};
+use leader::Leadership;
+use networks::Networks;
 use postgres::Postgres;
+use project::Projects;
 use prometheus::Registry;
+use quota::Ports;
+use shutdown::Shutdown;
 use thiserror::Error;
-use tracing::error;
 
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     io::{self},
+    sync::Arc,
 };
 
 #[derive(Error, Debug)]
@@ -14,6 +21,9 @@ pub enum Error {
     #[error("Postgres Error: {0}")]
     PgError(String),
 
+    #[error("Postgres Unreachable: {0}")]
+    PgUnreachable(String),
+
     #[error("Kube Error: {0}")]
     KubeError(#[source] kube::Error),
 
@@ -36,9 +46,57 @@ pub enum Error {
     HttpError(String),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Postgres,
+    PostgresUnreachable,
+    Kube,
+    Finalizer,
+    Prometheus,
+    Hash,
+    Config,
+    Http,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Postgres => "postgres",
+            ErrorKind::PostgresUnreachable => "postgres_unreachable",
+            ErrorKind::Kube => "kube",
+            ErrorKind::Finalizer => "finalizer",
+            ErrorKind::Prometheus => "prometheus",
+            ErrorKind::Hash => "hash",
+            ErrorKind::Config => "config",
+            ErrorKind::Http => "http",
+        }
+    }
+}
+
 impl Error {
-    pub fn metric_label(&self) -> String {
-        format!("{self:?}").to_lowercase()
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::PgError(_) => ErrorKind::Postgres,
+            Error::PgUnreachable(_) => ErrorKind::PostgresUnreachable,
+            Error::KubeError(_) => ErrorKind::Kube,
+            Error::FinalizerError(err) => match err.as_ref() {
+                finalizer::Error::ApplyFailed(err) | finalizer::Error::CleanupFailed(err) => {
+                    err.kind()
+                }
+                finalizer::Error::AddFinalizer(_) | finalizer::Error::RemoveFinalizer(_) => {
+                    ErrorKind::Kube
+                }
+                finalizer::Error::UnnamedObject => ErrorKind::Finalizer,
+            },
+            Error::PrometheusError(_) => ErrorKind::Prometheus,
+            Error::Sha256Error(_) | Error::Bech32Error(_) => ErrorKind::Hash,
+            Error::ConfigError(_) => ErrorKind::Config,
+            Error::HttpError(_) => ErrorKind::Http,
+        }
+    }
+
+    pub fn metric_label(&self) -> &'static str {
+        self.kind().as_str()
     }
 }
 
@@ -60,7 +118,7 @@ impl From<deadpool_postgres::BuildError> for Error {
 }
 impl From<deadpool_postgres::PoolError> for Error {
     fn from(value: deadpool_postgres::PoolError) -> Self {
-        Error::PgError(value.to_string())
+        Error::PgUnreachable(value.to_string())
     }
 }
 impl From<kube::Error> for Error {
@@ -88,8 +146,14 @@ impl From<reqwest::Error> for Error {
 pub struct State {
     registry: Registry,
     pub metrics: Metrics,
-    pub pg_connections: HashMap<String, Vec<Postgres>>,
+    pub networks: Arc<Networks>,
     pub kube_client: Client,
+    pub health: Arc<Health>,
+    pub leadership: Arc<Leadership>,
+    pub shutdown: Arc<Shutdown>,
+    pub instances: Arc<Instances>,
+    pub projects: Arc<Projects>,
+    pub ports: Arc<Ports>,
 }
 impl State {
     pub async fn try_new() -> Result<Self, Error> {
@@ -98,35 +162,70 @@ impl State {
         let registry = Registry::default();
         let metrics = Metrics::default().register(&registry).unwrap();
 
-        let mut pg_connections: HashMap<String, Vec<Postgres>> = HashMap::new();
-        for (network, db_name) in config.db_names.iter() {
-            let mut connections: Vec<Postgres> = Vec::new();
-            for url in config.db_urls.iter() {
-                let connection =
-                    Postgres::try_new(&format!("{url}/{db_name}"), &config.db_max_connections)
-                        .await?;
-                connections.push(connection);
-            }
-
-            pg_connections.insert(network.clone(), connections);
-        }
+        let networks = Networks::from_config(config).await?;
 
         let kube_client = Client::try_default().await?;
 
         Ok(Self {
             registry,
             metrics,
-            pg_connections,
+            networks: Arc::new(networks),
             kube_client,
+            health: Arc::new(Health::default()),
+            leadership: Arc::new(Leadership::default()),
+            shutdown: Arc::new(Shutdown::default()),
+            instances: Arc::new(Instances::default()),
+            projects: Arc::new(Projects::new(config.project_sources.clone())),
+            ports: Arc::new(Ports::default()),
         })
     }
 
     pub fn metrics_collected(&self) -> Vec<prometheus::proto::MetricFamily> {
+        for (network, connections) in self.pg_connections().iter() {
+            for pg in connections.iter() {
+                self.metrics.pool_status(network, pg);
+            }
+        }
+
         self.registry.gather()
     }
 
-    pub fn get_pg_by_network(&self, network: &str) -> Result<&Vec<Postgres>, Error> {
-        if let Some(connections) = self.pg_connections.get(network) {
+    pub fn close_pools(&self) {
+        self.networks.close();
+    }
+
+    // Usage and metrics of a port whose project can't be resolved are reported with an empty
+    // project, instead of failing the reconcile or the collector.
+    pub async fn project(&self, namespace: &str, project_ref: Option<&str>) -> String {
+        let project = self
+            .projects
+            .resolve(&self.kube_client, namespace, project_ref)
+            .await;
+        self.project_or_unresolved(namespace, project)
+    }
+
+    pub fn cached_project(&self, namespace: &str, project_ref: Option<&str>) -> String {
+        let project = self.projects.resolve_cached(namespace, project_ref);
+        self.project_or_unresolved(namespace, project)
+    }
+
+    fn project_or_unresolved(&self, namespace: &str, project: Option<String>) -> String {
+        project.unwrap_or_else(|| {
+            if self.projects.report_unresolved(namespace) {
+                self.metrics.count_unresolved_project(namespace);
+            }
+            String::new()
+        })
+    }
+
+    // The pools of every network of the catalogue, by canonical name
+    pub fn pg_connections(&self) -> BTreeMap<String, Vec<Postgres>> {
+        self.networks.snapshot()
+    }
+
+    // Accepts the aliases of the networks too
+    pub fn get_pg_by_network(&self, network: &str) -> Result<Vec<Postgres>, Error> {
+        if let Some(connections) = self.networks.connections(network) {
             return Ok(connections);
         }
 
@@ -137,12 +236,52 @@ impl State {
 }
 
 pub mod controller;
+pub mod conversion;
+pub mod credentials;
+pub mod events;
+pub mod health;
+pub mod instances;
+pub mod internal_user;
+pub mod leader;
 pub mod metrics;
+pub mod networks;
 pub mod postgres;
+pub mod project;
+pub mod quota;
+pub mod schema;
+pub mod shutdown;
+pub mod stats;
+pub mod sync;
+pub mod telemetry;
+pub mod username;
 pub mod utils;
+pub mod v1alpha1;
 
 pub use controller::*;
+pub use internal_user::*;
 pub use metrics::*;
 
 mod config;
 pub use config::*;
+
+#[cfg(test)]
+mod fixtures;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_label() {
+        let error = Error::PgError("connection refused to 10.0.0.1:5432".into());
+        assert_eq!(error.metric_label(), "postgres");
+
+        let error = Error::FinalizerError(Box::new(finalizer::Error::ApplyFailed(
+            Error::ConfigError("postgres not configured to banana".into()),
+        )));
+        assert_eq!(error.metric_label(), "config");
+
+        let error = Error::FinalizerError(Box::new(finalizer::Error::UnnamedObject));
+        assert_eq!(error.metric_label(), "finalizer");
+    }
+}
@@ -1,12 +1,31 @@
 use actix_web::{
-    get, middleware, web::Data, App, HttpRequest, HttpResponse, HttpServer, Responder,
+    dev::Server,
+    get, middleware, post,
+    web::{Data, Json},
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use dotenv::dotenv;
+use kube::core::{
+    conversion::{ConversionRequest, ConversionResponse, ConversionReview},
+    response::Status,
+};
 use prometheus::{Encoder, TextEncoder};
-use std::{io, sync::Arc};
-use tracing::{info, Level};
+use serde_json::json;
+use std::{fs::File, io, io::BufReader, sync::Arc, time::Instant};
+use tracing::{info, warn};
 
-use ext_cardano_dbsync::{controller, metrics as metrics_collector, State};
+use ext_cardano_dbsync::{
+    controller, conversion, get_config,
+    health::{supervise, COLLECTOR_LOOP, CONTROLLER_LOOP, SCHEMA_LOOP},
+    instances::run_instance_checks,
+    leader::{release_lease, run_leader_election},
+    metrics as metrics_collector,
+    networks::run_network_watcher,
+    schema::run_schema_bootstrap,
+    telemetry,
+    utils::shutdown_signal,
+    State,
+};
 
 #[get("/metrics")]
 async fn metrics(c: Data<Arc<State>>, _req: HttpRequest) -> impl Responder {
@@ -14,38 +33,191 @@ async fn metrics(c: Data<Arc<State>>, _req: HttpRequest) -> impl Responder {
     let encoder = TextEncoder::new();
     let mut buffer = vec![];
     encoder.encode(&metrics, &mut buffer).unwrap();
+    c.shutdown.scraped();
     HttpResponse::Ok().body(buffer)
 }
 
 #[get("/health")]
-async fn health(_: HttpRequest) -> impl Responder {
+async fn health(c: Data<Arc<State>>, _req: HttpRequest) -> impl Responder {
+    let stalled = c.health.stalled_loops();
+    if !stalled.is_empty() {
+        return HttpResponse::ServiceUnavailable().json(json!({ "stalled": stalled }));
+    }
+
     HttpResponse::Ok().json("healthy")
 }
 
+#[get("/ready")]
+async fn ready(c: Data<Arc<State>>, _req: HttpRequest) -> impl Responder {
+    let stalled = c.health.stalled_loops();
+    let pending = c.health.pending_loops();
+    if !stalled.is_empty() || !pending.is_empty() {
+        return HttpResponse::ServiceUnavailable()
+            .json(json!({ "stalled": stalled, "pending": pending }));
+    }
+
+    HttpResponse::Ok().json("ready")
+}
+
+#[post("/convert")]
+async fn convert(review: Json<ConversionReview>) -> impl Responder {
+    let response = match ConversionRequest::from_review(review.into_inner()) {
+        Ok(request) => conversion::convert(request),
+        Err(err) => {
+            ConversionResponse::invalid(Status::failure(&err.to_string(), "InvalidRequest"))
+        }
+    };
+    HttpResponse::Ok().json(response.into_review())
+}
+
+fn tls_config(cert: &str, key: &str) -> io::Result<rustls::ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+
+    // cert-manager writes PKCS#1 keys unless told otherwise
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "CONVERSION_TLS_KEY has no private key",
+        ))?;
+
+    rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// The API server only calls conversion webhooks over https, so it's served apart from the
+// metrics, and only when a certificate is mounted.
+fn webhook_server() -> io::Result<Option<Server>> {
+    let config = get_config();
+    let (Some(cert), Some(key)) = (&config.conversion_tls_cert, &config.conversion_tls_key) else {
+        warn!("CONVERSION_TLS_CERT and CONVERSION_TLS_KEY not set, conversion webhook disabled");
+        return Ok(None);
+    };
+
+    let addr = std::env::var("WEBHOOK_ADDR").unwrap_or("0.0.0.0:9443".into());
+    let server = HttpServer::new(|| {
+        App::new()
+            .wrap(middleware::Logger::default())
+            .service(convert)
+    })
+    .disable_signals()
+    .bind_rustls_021(&addr, tls_config(cert, key)?)?
+    .run();
+    info!({ addr }, "conversion webhook running");
+
+    Ok(Some(server))
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
     dotenv().ok();
 
-    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+    let tracer_provider = telemetry::init()?;
 
     let state = Arc::new(State::try_new().await?);
 
-    let controller = controller::run(state.clone());
-    let metrics_collector = metrics_collector::run_metrics_collector(state.clone());
+    let config = get_config();
+
+    let shutdown = state.shutdown.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("shutdown requested");
+        shutdown.trigger();
+    });
+
+    let election = tokio::spawn(run_leader_election(state.clone()));
+    tokio::spawn(supervise(SCHEMA_LOOP, state.clone(), run_schema_bootstrap));
+    tokio::spawn(run_instance_checks(state.clone()));
+    tokio::spawn(run_network_watcher(state.clone()));
+
+    let controller = tokio::spawn(supervise(CONTROLLER_LOOP, state.clone(), controller::run));
+    let collector = tokio::spawn(supervise(
+        COLLECTOR_LOOP,
+        state.clone(),
+        metrics_collector::run_metrics_collector,
+    ));
 
     let addr = std::env::var("ADDR").unwrap_or("0.0.0.0:8080".into());
 
+    let server_state = state.clone();
     let server = HttpServer::new(move || {
         App::new()
-            .app_data(Data::new(state.clone()))
+            .app_data(Data::new(server_state.clone()))
             .wrap(middleware::Logger::default())
             .service(health)
+            .service(ready)
             .service(metrics)
     })
-    .bind(&addr)?;
+    .disable_signals()
+    .bind(&addr)?
+    .run();
     info!({ addr }, "metrics server running");
 
-    tokio::join!(server.run(), controller, metrics_collector).0?;
+    let server_handle = server.handle();
+    let mut server = tokio::spawn(server);
+
+    let webhook = webhook_server()?;
+    let webhook_handle = webhook.as_ref().map(|webhook| webhook.handle());
+    if let Some(webhook) = webhook {
+        tokio::spawn(webhook);
+    }
+
+    tokio::select! {
+        _ = state.shutdown.wait() => {},
+        result = &mut server => {
+            state.shutdown.trigger();
+            result??;
+        },
+    }
+
+    let shutdown_started = Instant::now();
+    let drained = tokio::time::timeout(config.shutdown_grace_period, async {
+        let _ = tokio::join!(controller, collector);
+    })
+    .await;
+    if drained.is_err() {
+        warn!("shutdown grace period elapsed before the loops finished");
+    }
+
+    // The collector of the leader recorded a last usage window, it's only kept if it's scraped
+    let collected = state.leadership.is_leader();
+    election.abort();
+    release_lease(&state).await;
+
+    if collected {
+        let remaining = config
+            .shutdown_grace_period
+            .saturating_sub(shutdown_started.elapsed());
+        if tokio::time::timeout(remaining, state.shutdown.next_scrape())
+            .await
+            .is_err()
+        {
+            warn!("shutdown grace period elapsed before the last usage window was scraped");
+        }
+    }
+
+    server_handle.stop(true).await;
+    if let Some(webhook_handle) = webhook_handle {
+        webhook_handle.stop(true).await;
+    }
+    state.close_pools();
+    info!("shutdown completed");
+
+    if let Some(tracer_provider) = tracer_provider {
+        let _ = tracer_provider.shutdown();
+    }
 
     Ok(())
 }
@@ -1,11 +1,25 @@
-use chrono::Utc;
-use kube::{api::ListParams, core::object::HasSpec, Api, Client, Resource, ResourceExt};
-use prometheus::{opts, IntCounterVec, Registry};
+use chrono::{DateTime, Utc};
+use kube::{api::ListParams, Api, Client, Resource, ResourceExt};
+use prometheus::{
+    histogram_opts, opts, GaugeVec, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Registry,
+};
 use serde::{Deserialize, Deserializer};
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tracing::{error, info, instrument, warn};
 
-use crate::{get_config, Config, DbSyncPort, Error, State};
+use crate::{
+    get_config, health::COLLECTOR_LOOP, health::CONTROLLER_LOOP, instances::InstanceState,
+    postgres::Postgres, quota, stats::collect_port_stats, sync::collect_sync_status, Config,
+    DbSyncPort, Error, State,
+};
+
+// Tier of the usage of the internal service accounts
+const INTERNAL_TIER: &str = "internal";
 
 #[derive(Clone)]
 pub struct Metrics {
@@ -14,6 +28,27 @@ pub struct Metrics {
     pub reconcile_failures: IntCounterVec,
     pub metrics_failures: IntCounterVec,
     pub usage: IntCounterVec,
+    pub last_successful_collection: IntGauge,
+    pub last_reconcile: IntGauge,
+    pub loop_duration: GaugeVec,
+    pub loop_restarts: IntCounterVec,
+    pub reconcile_duration: HistogramVec,
+    pub pg_pool_size: IntGaugeVec,
+    pub pg_pool_available: IntGaugeVec,
+    pub pg_pool_waiters: IntGaugeVec,
+    pub pg_roles: IntGaugeVec,
+    pub prometheus_query_duration: Histogram,
+    pub ports: IntGaugeVec,
+    pub leader: IntGauge,
+    pub leadership_changes: IntCounter,
+    pub schema_ready: IntGaugeVec,
+    pub schema_missing: IntGaugeVec,
+    pub sync_lag: GaugeVec,
+    pub tip_slot: IntGaugeVec,
+    pub instance_states: IntGaugeVec,
+    pub credential_expiry: IntGaugeVec,
+    pub credential_renewals: IntCounterVec,
+    pub unresolved_projects: IntCounterVec,
 }
 
 impl Default for Metrics {
@@ -39,16 +74,17 @@ impl Default for Metrics {
         let reconcile_failures = IntCounterVec::new(
             opts!(
                 "dmtr_dbsync_reconciliation_errors_total",
-                "reconciliation errors",
+                "reconciliation errors by error kind (postgres, postgres_unreachable, kube, finalizer, prometheus, hash, config, http). \
+                 Migration: the error label no longer carries the error message and instance is now paired with the namespace and project labels",
             ),
-            &["instance", "error"],
+            &["project", "namespace", "instance", "error"],
         )
         .unwrap();
 
         let metrics_failures = IntCounterVec::new(
             opts!(
                 "dmtr_dbsync_metrics_errors_total",
-                "errors to calculation metrics",
+                "errors to calculation metrics by error kind",
             ),
             &["error"],
         )
@@ -60,12 +96,198 @@ impl Default for Metrics {
         )
         .unwrap();
 
+        let last_successful_collection = IntGauge::with_opts(opts!(
+            "dmtr_dbsync_last_successful_collection_timestamp_seconds",
+            "unix timestamp of the last successful usage collection",
+        ))
+        .unwrap();
+
+        let last_reconcile = IntGauge::with_opts(opts!(
+            "dmtr_dbsync_last_reconcile_timestamp_seconds",
+            "unix timestamp of the last finished reconcile",
+        ))
+        .unwrap();
+
+        let loop_duration = GaugeVec::new(
+            opts!(
+                "dmtr_dbsync_loop_duration_seconds",
+                "duration of the last iteration of each loop",
+            ),
+            &["loop"],
+        )
+        .unwrap();
+
+        let loop_restarts = IntCounterVec::new(
+            opts!(
+                "dmtr_dbsync_loop_restarts_total",
+                "total of loops restarted after a panic",
+            ),
+            &["loop"],
+        )
+        .unwrap();
+
+        let reconcile_duration = HistogramVec::new(
+            histogram_opts!(
+                "dmtr_dbsync_reconcile_duration_seconds",
+                "duration of reconciles by outcome",
+            ),
+            &["outcome"],
+        )
+        .unwrap();
+
+        let pg_pool_size = IntGaugeVec::new(
+            opts!(
+                "dmtr_dbsync_pg_pool_size",
+                "current connections of the postgres pool",
+            ),
+            &["network", "instance"],
+        )
+        .unwrap();
+
+        let pg_pool_available = IntGaugeVec::new(
+            opts!(
+                "dmtr_dbsync_pg_pool_available",
+                "idle connections of the postgres pool",
+            ),
+            &["network", "instance"],
+        )
+        .unwrap();
+
+        let pg_pool_waiters = IntGaugeVec::new(
+            opts!(
+                "dmtr_dbsync_pg_pool_waiters",
+                "tasks waiting for a connection of the postgres pool",
+            ),
+            &["network", "instance"],
+        )
+        .unwrap();
+
+        let pg_roles = IntGaugeVec::new(
+            opts!("dmtr_dbsync_pg_roles", "login roles that exist in postgres",),
+            &["network", "instance"],
+        )
+        .unwrap();
+
+        let prometheus_query_duration = Histogram::with_opts(histogram_opts!(
+            "dmtr_dbsync_prometheus_query_duration_seconds",
+            "latency of the usage queries to prometheus",
+        ))
+        .unwrap();
+
+        let ports = IntGaugeVec::new(
+            opts!("dmtr_dbsync_ports", "active dbsync ports"),
+            &["network", "tier"],
+        )
+        .unwrap();
+
+        let leader = IntGauge::with_opts(opts!(
+            "dmtr_dbsync_leader",
+            "1 when this replica holds the leader lease",
+        ))
+        .unwrap();
+
+        let leadership_changes = IntCounter::with_opts(opts!(
+            "dmtr_dbsync_leadership_changes_total",
+            "total of leadership acquisitions and losses of this replica",
+        ))
+        .unwrap();
+
+        let schema_ready = IntGaugeVec::new(
+            opts!(
+                "dmtr_dbsync_schema_ready",
+                "1 when the extensions, roles and indexes expected by the ports exist",
+            ),
+            &["network", "instance"],
+        )
+        .unwrap();
+
+        let schema_missing = IntGaugeVec::new(
+            opts!(
+                "dmtr_dbsync_schema_missing",
+                "prerequisites missing on a database, indexes are counted one by one",
+            ),
+            &["network", "instance", "prerequisite"],
+        )
+        .unwrap();
+
+        let sync_lag = GaugeVec::new(
+            opts!(
+                "dmtr_dbsync_sync_lag_seconds",
+                "seconds between the wall clock and the time of the latest block in dbsync",
+            ),
+            &["network", "instance"],
+        )
+        .unwrap();
+
+        let tip_slot = IntGaugeVec::new(
+            opts!("dmtr_dbsync_tip_slot", "slot of the latest block in dbsync"),
+            &["network", "instance"],
+        )
+        .unwrap();
+
+        let instance_states = IntGaugeVec::new(
+            opts!(
+                "dmtr_dbsync_instance_state",
+                "1 for the current state of each database: healthy, degraded or excluded",
+            ),
+            &["network", "instance", "state"],
+        )
+        .unwrap();
+
+        let credential_expiry = IntGaugeVec::new(
+            opts!(
+                "dmtr_dbsync_credential_expiry_timestamp_seconds",
+                "unix timestamp when the password of a port with a credentialTtl expires",
+            ),
+            &["namespace", "port"],
+        )
+        .unwrap();
+
+        let credential_renewals = IntCounterVec::new(
+            opts!(
+                "dmtr_dbsync_credential_renewals_total",
+                "password renewals of the ports with a credentialTtl by result",
+            ),
+            &["project", "result"],
+        )
+        .unwrap();
+
+        let unresolved_projects = IntCounterVec::new(
+            opts!(
+                "dmtr_dbsync_unresolved_projects_total",
+                "namespaces whose project no PROJECT_SOURCES resolved, counted once each time the namespace is read",
+            ),
+            &["namespace"],
+        )
+        .unwrap();
+
         Metrics {
             users_created,
             users_dropped,
             reconcile_failures,
             metrics_failures,
             usage,
+            last_successful_collection,
+            last_reconcile,
+            loop_duration,
+            loop_restarts,
+            reconcile_duration,
+            pg_pool_size,
+            pg_pool_available,
+            pg_pool_waiters,
+            pg_roles,
+            prometheus_query_duration,
+            ports,
+            leader,
+            leadership_changes,
+            schema_ready,
+            schema_missing,
+            sync_lag,
+            tip_slot,
+            instance_states,
+            credential_expiry,
+            credential_renewals,
+            unresolved_projects,
         }
     }
 }
@@ -73,35 +295,175 @@ impl Default for Metrics {
 impl Metrics {
     pub fn register(self, registry: &Registry) -> Result<Self, prometheus::Error> {
         registry.register(Box::new(self.reconcile_failures.clone()))?;
+        registry.register(Box::new(self.metrics_failures.clone()))?;
         registry.register(Box::new(self.users_created.clone()))?;
         registry.register(Box::new(self.users_dropped.clone()))?;
         registry.register(Box::new(self.usage.clone()))?;
+        registry.register(Box::new(self.last_successful_collection.clone()))?;
+        registry.register(Box::new(self.last_reconcile.clone()))?;
+        registry.register(Box::new(self.loop_duration.clone()))?;
+        registry.register(Box::new(self.loop_restarts.clone()))?;
+        registry.register(Box::new(self.reconcile_duration.clone()))?;
+        registry.register(Box::new(self.pg_pool_size.clone()))?;
+        registry.register(Box::new(self.pg_pool_available.clone()))?;
+        registry.register(Box::new(self.pg_pool_waiters.clone()))?;
+        registry.register(Box::new(self.pg_roles.clone()))?;
+        registry.register(Box::new(self.prometheus_query_duration.clone()))?;
+        registry.register(Box::new(self.ports.clone()))?;
+        registry.register(Box::new(self.leader.clone()))?;
+        registry.register(Box::new(self.leadership_changes.clone()))?;
+        registry.register(Box::new(self.schema_ready.clone()))?;
+        registry.register(Box::new(self.schema_missing.clone()))?;
+        registry.register(Box::new(self.sync_lag.clone()))?;
+        registry.register(Box::new(self.tip_slot.clone()))?;
+        registry.register(Box::new(self.instance_states.clone()))?;
+        registry.register(Box::new(self.credential_expiry.clone()))?;
+        registry.register(Box::new(self.credential_renewals.clone()))?;
+        registry.register(Box::new(self.unresolved_projects.clone()))?;
         Ok(self)
     }
 
-    pub fn reconcile_failure(&self, crd: &DbSyncPort, e: &Error) {
+    // Cluster-scoped resources, like InternalDbUser, don't belong to a project
+    pub fn reconcile_failure<K: ResourceExt>(&self, crd: &K, project: &str, e: &Error) {
+        let namespace = crd.namespace().unwrap_or_default();
         self.reconcile_failures
-            .with_label_values(&[crd.name_any().as_ref(), e.metric_label().as_ref()])
+            .with_label_values(&[project, &namespace, &crd.name_any(), e.metric_label()])
+            .inc()
+    }
+
+    pub fn count_unresolved_project(&self, namespace: &str) {
+        self.unresolved_projects
+            .with_label_values(&[namespace])
             .inc()
     }
 
     pub fn metrics_failure(&self, e: &Error) {
         self.metrics_failures
-            .with_label_values(&[e.metric_label().as_ref()])
+            .with_label_values(&[e.metric_label()])
             .inc()
     }
 
-    pub fn count_user_created(&self, namespace: &str, network: &str) {
-        let project = get_project_id(namespace);
+    pub fn collection_succeeded(&self, duration: Duration) {
+        self.last_successful_collection.set(Utc::now().timestamp());
+        self.loop_duration
+            .with_label_values(&[COLLECTOR_LOOP])
+            .set(duration.as_secs_f64());
+    }
+
+    pub fn reconcile_finished(&self, duration: Duration, success: bool) {
+        let outcome = if success { "success" } else { "failure" };
+        self.reconcile_duration
+            .with_label_values(&[outcome])
+            .observe(duration.as_secs_f64());
+
+        self.last_reconcile.set(Utc::now().timestamp());
+        self.loop_duration
+            .with_label_values(&[CONTROLLER_LOOP])
+            .set(duration.as_secs_f64());
+    }
+
+    pub fn pool_status(&self, network: &str, pg: &Postgres) {
+        let status = pg.status();
+        let labels = [network, pg.instance.as_str()];
+
+        self.pg_pool_size
+            .with_label_values(&labels)
+            .set(status.size as i64);
+        self.pg_pool_available
+            .with_label_values(&labels)
+            .set(status.available as i64);
+        self.pg_pool_waiters
+            .with_label_values(&labels)
+            .set(status.waiting as i64);
+    }
+
+    pub fn roles_count(&self, network: &str, instance: &str, count: i64) {
+        self.pg_roles
+            .with_label_values(&[network, instance])
+            .set(count);
+    }
+
+    pub fn ports_count(&self, crds: &[DbSyncPort]) {
+        self.ports.reset();
+        for crd in crds {
+            let tier = quota::applied_tier(crd).to_string();
+            self.ports
+                .with_label_values(&[crd.spec.network.as_str(), &tier])
+                .inc();
+        }
+    }
+
+    pub fn schema_status(&self, network: &str, instance: &str, missing: &[(&str, i64)]) {
+        let ready = missing.iter().all(|(_, count)| *count == 0);
+        self.schema_ready
+            .with_label_values(&[network, instance])
+            .set(ready.into());
+
+        for (prerequisite, count) in missing {
+            self.schema_missing
+                .with_label_values(&[network, instance, prerequisite])
+                .set(*count);
+        }
+    }
+
+    pub fn sync_status(&self, network: &str, instance: &str, slot: i64, lag: f64) {
+        self.tip_slot
+            .with_label_values(&[network, instance])
+            .set(slot);
+        self.sync_lag
+            .with_label_values(&[network, instance])
+            .set(lag);
+    }
+
+    pub fn instance_state(&self, network: &str, instance: &str, state: InstanceState) {
+        for candidate in [
+            InstanceState::Healthy,
+            InstanceState::Degraded,
+            InstanceState::Excluded,
+        ] {
+            self.instance_states
+                .with_label_values(&[network, instance, candidate.as_str()])
+                .set((candidate == state).into());
+        }
+    }
+
+    pub fn credential_expires(&self, namespace: &str, port: &str, expires_at: DateTime<Utc>) {
+        self.credential_expiry
+            .with_label_values(&[namespace, port])
+            .set(expires_at.timestamp());
+    }
+
+    pub fn credential_dropped(&self, namespace: &str, port: &str) {
+        let _ = self
+            .credential_expiry
+            .remove_label_values(&[namespace, port]);
+    }
+
+    pub fn count_credential_renewal(&self, project: &str, renewed: bool) {
+        let result = if renewed { "renewed" } else { "failed" };
+        self.credential_renewals
+            .with_label_values(&[project, result])
+            .inc();
+    }
+
+    pub fn leadership_changed(&self, is_leader: bool) {
+        self.leader.set(is_leader.into());
+        self.leadership_changes.inc();
+    }
+
+    pub fn count_loop_restart(&self, name: &str) {
+        self.loop_restarts.with_label_values(&[name]).inc();
+    }
+
+    pub fn count_user_created(&self, project: &str, network: &str) {
         self.users_created
-            .with_label_values(&[&project, network])
+            .with_label_values(&[project, network])
             .inc();
     }
 
-    pub fn count_user_dropped(&self, namespace: &str, network: &str) {
-        let project = get_project_id(namespace);
+    pub fn count_user_dropped(&self, project: &str, network: &str) {
         self.users_dropped
-            .with_label_values(&[&project, network])
+            .with_label_values(&[project, network])
             .inc();
     }
 
@@ -113,98 +475,210 @@ impl Metrics {
             .with_label_values(&[feature, project, resource_name, tier])
             .inc_by(value);
     }
-}
 
-fn get_project_id(namespace: &str) -> String {
-    namespace.split_once("prj-").unwrap().1.into()
+    // Internal accounts are metered next to the ports, with the username as the resource
+    pub fn count_internal_usage(&self, project: &str, username: &str, value: f64) {
+        self.count_usage(project, username, INTERNAL_TIER, value);
+    }
 }
 
 #[instrument("metrics collector run", skip_all)]
 pub async fn run_metrics_collector(state: Arc<State>) {
-    tokio::spawn(async move {
-        info!("collecting metrics running");
+    info!("collecting metrics running");
+
+    let client = Client::try_default()
+        .await
+        .expect("failed to create kube client");
+
+    let crds_api = Api::<DbSyncPort>::all(client.clone());
+
+    let config = get_config();
+    let mut last_execution = Utc::now();
+
+    let current_namespace = client.default_namespace().to_string();
+    let mut exec_times = HashMap::new();
 
-        let client = Client::try_default()
-            .await
-            .expect("failed to create kube client");
+    loop {
+        // On shutdown the pending window is collected right away, so no usage is lost
+        let stopping = tokio::select! {
+            _ = tokio::time::sleep(config.metrics_delay) => false,
+            _ = state.shutdown.wait() => true,
+        };
 
-        let crds_api = Api::<DbSyncPort>::all(client.clone());
+        let started = Instant::now();
+        state.health.tick_started(COLLECTOR_LOOP);
 
-        let config = get_config();
-        let mut last_execution = Utc::now();
+        if !state.leadership.is_leader() {
+            last_execution = Utc::now();
+            state.health.tick_finished(COLLECTOR_LOOP, true);
+            if stopping {
+                return;
+            }
+            continue;
+        }
 
-        let current_namespace = client.default_namespace();
+        let result = match crds_api.list(&ListParams::default()).await {
+            Ok(crds) => {
+                state.metrics.ports_count(&crds.items);
 
-        loop {
-            tokio::time::sleep(config.metrics_delay).await;
+                let result =
+                    collect_usage(&state, &crds.items, &current_namespace, &mut last_execution)
+                        .await;
+                if !stopping {
+                    collect_port_stats(&state, &crds.items, &mut exec_times).await;
+                    collect_sync_status(&state, &crds.items).await;
+                }
 
-            let crds_result = crds_api.list(&ListParams::default()).await;
-            if let Err(error) = crds_result {
+                result
+            }
+            Err(error) => {
                 error!(error = error.to_string(), "error to get k8s resources");
-                state.metrics.metrics_failure(&error.into());
+                let error = error.into();
+                state.metrics.metrics_failure(&error);
+                Err(error)
+            }
+        };
+
+        if !stopping {
+            collect_roles(&state).await;
+        }
+
+        state.health.tick_finished(COLLECTOR_LOOP, result.is_ok());
+        if result.is_ok() {
+            state.metrics.collection_succeeded(started.elapsed());
+        }
+
+        if stopping {
+            info!("final usage window collected");
+            return;
+        }
+    }
+}
+
+async fn collect_usage(
+    state: &State,
+    crds: &[DbSyncPort],
+    current_namespace: &str,
+    last_execution: &mut DateTime<Utc>,
+) -> Result<(), Error> {
+    let config = get_config();
+
+    let end = Utc::now();
+    let interval = (end - *last_execution).num_seconds();
+
+    *last_execution = end;
+
+    let internal_users: Vec<&str> = config.internal_users.keys().map(String::as_str).collect();
+    let usename_regex = usename_regex(&config.username_prefix, crds, &internal_users);
+
+    let query = format!(
+        "sum by (usename) (avg_over_time(pg_stat_activity_count{{usename=~\"{usename_regex}\", namespace=\"{current_namespace}\"}}[{interval}s] @ {})) > 0",
+        end.timestamp_millis() / 1000
+    );
+
+    let timer = state.metrics.prometheus_query_duration.start_timer();
+    let response = collect_prometheus_metrics(config, query).await;
+    timer.observe_duration();
+
+    let response = match response {
+        Ok(response) => response,
+        Err(err) => {
+            error!(error = err.to_string(), "error to make prometheus request");
+            state.metrics.metrics_failure(&err);
+            return Err(err);
+        }
+    };
+
+    for result in response.data.result {
+        let total_exec_time = result.value * (interval as f64);
+
+        if let Some(project) = config.internal_users.get(&result.metric.usename) {
+            state
+                .metrics
+                .count_internal_usage(project, &result.metric.usename, total_exec_time);
+            continue;
+        }
+
+        // Every credential of a port is metered as the port itself
+        let crd = match crds.iter().filter(|c| c.status.is_some()).find(|c| {
+            c.status
+                .as_ref()
+                .unwrap()
+                .usernames()
+                .contains(&result.metric.usename.as_str())
+        }) {
+            Some(crd) => crd,
+            None => {
+                warn!(user = result.metric.usename, "username doesnt have a crd");
                 continue;
             }
-            let crds = crds_result.unwrap();
+        };
 
-            let end = Utc::now();
-            let interval = (end - last_execution).num_seconds();
+        let project = crd.project(state).await;
 
-            last_execution = end;
+        state.metrics.count_usage(
+            &project,
+            &crd.name_any(),
+            &quota::applied_tier(crd).to_string(),
+            total_exec_time,
+        );
+    }
 
-            let query = format!(
-                "sum by (usename) (avg_over_time(pg_stat_activity_count{{usename=~\".*dbsync.*\", namespace=\"{current_namespace}\"}}[{interval}s] @ {})) > 0",
-                end.timestamp_millis() / 1000
-            );
+    Ok(())
+}
 
-            let response = match collect_prometheus_metrics(config, query).await {
-                Ok(response) => response,
+async fn collect_roles(state: &State) {
+    for (network, connections) in state.pg_connections().iter() {
+        for pg in connections {
+            match pg.count_roles().await {
+                Ok(count) => state.metrics.roles_count(network, &pg.instance, count),
                 Err(err) => {
-                    error!(error = err.to_string(), "error to make prometheus request");
+                    error!(
+                        error = err.to_string(),
+                        network, "error to count postgres roles"
+                    );
                     state.metrics.metrics_failure(&err);
-                    continue;
                 }
-            };
-
-            for result in response.data.result {
-                let crd = match crds.iter().filter(|c| c.status.is_some()).find(|c| {
-                    c.status
-                        .as_ref()
-                        .unwrap()
-                        .username
-                        .eq(&result.metric.usename)
-                }) {
-                    Some(crd) => crd,
-                    None => {
-                        if result.metric.usename != "dmtr_blockfrost" {
-                            warn!(user = result.metric.usename, "username doesnt have a crd");
-                        }
-                        continue;
-                    }
-                };
-
-                let total_exec_time = result.value * (interval as f64);
-
-                let project = get_project_id(&crd.namespace().unwrap());
-
-                state.metrics.count_usage(
-                    &project,
-                    &crd.name_any(),
-                    &crd.spec()
-                        .throughput_tier
-                        .clone()
-                        .unwrap_or("0".to_string()),
-                    total_exec_time,
-                );
             }
         }
-    });
+    }
+}
+
+// The generated usernames by their prefix, and the ones set on a port or configured for an
+// internal service by name
+fn usename_regex(prefix: &str, crds: &[DbSyncPort], internal_users: &[&str]) -> String {
+    let mut usernames: Vec<&str> = crds
+        .iter()
+        .filter_map(|crd| crd.status.as_ref())
+        .flat_map(|status| status.usernames())
+        .filter(|username| !username.starts_with(prefix))
+        .chain(internal_users.iter().copied())
+        .collect();
+    usernames.sort();
+    usernames.dedup();
+
+    std::iter::once(format!("{}.*", escape_regex(prefix)))
+        .chain(usernames.into_iter().map(escape_regex))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+// Escapes a literal for a regex inside a PromQL string, so `.` becomes `\\.`.
+fn escape_regex(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '-' => c.to_string(),
+            c => format!("\\\\{c}"),
+        })
+        .collect()
 }
 
 async fn collect_prometheus_metrics(
     config: &Config,
     query: String,
 ) -> Result<PrometheusResponse, Error> {
-    let client = reqwest::Client::builder().build().unwrap();
+    let client = reqwest::Client::builder().build()?;
 
     let response = client
         .get(format!("{}/query?query={query}", config.prometheus_url))
@@ -219,7 +693,13 @@ async fn collect_prometheus_metrics(
         )));
     }
 
-    Ok(response.json().await.unwrap())
+    // An unexpected body is a failed collection, not a panic of the collector
+    let body = response.text().await?;
+    serde_json::from_str(&body).map_err(|err| {
+        Error::HttpError(format!(
+            "Prometheus response can't be read: {err}. Query: {query}"
+        ))
+    })
 }
 
 #[derive(Debug, Deserialize)]
@@ -250,9 +730,51 @@ where
     D: Deserializer<'de>,
 {
     let value: Vec<serde_json::Value> = Deserialize::deserialize(deserializer)?;
-    Ok(value.into_iter().as_slice()[1]
-        .as_str()
-        .unwrap()
+    value
+        .get(1)
+        .and_then(|value| value.as_str())
+        .ok_or_else(|| serde::de::Error::custom("expected a [timestamp, \"value\"] pair"))?
         .parse::<f64>()
-        .unwrap())
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn port(usernames: &[&str]) -> DbSyncPort {
+        fixtures::provisioned(fixtures::port("port", "cardano-mainnet"), usernames)
+    }
+
+    #[test]
+    fn test_usename_regex() {
+        let crds = vec![
+            port(&["dmtr_dbsync1a2b"]),
+            port(&["app_user", "app_user2"]),
+            port(&["app_user"]),
+        ];
+        assert_eq!(
+            usename_regex("dmtr_dbsync", &crds, &["dmtrro"]),
+            "dmtr_dbsync.*|app_user|app_user2|dmtrro"
+        );
+        assert_eq!(usename_regex("dmtr.dbsync", &[], &[]), "dmtr\\\\.dbsync.*");
+    }
+
+    #[test]
+    fn test_prometheus_response() {
+        let body =
+            r#"{"data":{"result":[{"metric":{"usename":"app_user"},"value":[1700000000,"2.5"]}]}}"#;
+        let response: PrometheusResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(response.data.result[0].value, 2.5);
+
+        for body in [
+            r#"{"data":{"result":[{"metric":{"usename":"app_user"},"value":[1700000000]}]}}"#,
+            r#"{"data":{"result":[{"metric":{"usename":"app_user"},"value":[1700000000,"NaN?"]}]}}"#,
+            r#"{"data":{"result":[{"metric":{"usename":"app_user"},"value":[1700000000,2.5]}]}}"#,
+            r#"{"status":"error"}"#,
+        ] {
+            assert!(serde_json::from_str::<PrometheusResponse>(body).is_err());
+        }
+    }
 }
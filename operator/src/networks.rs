@@ -0,0 +1,442 @@
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Secret;
+use kube::{
+    runtime::{events::EventType, watcher, WatchStreamExt},
+    Api, Client, CustomResource, ResourceExt,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::{info, instrument, warn};
+
+use crate::{events, postgres::Postgres, utils::legacy_aliases, Config, Error, State};
+
+// Credentials in the Secrets can be rotated without touching the network, they are read again
+// after this
+const NETWORK_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+const DEFAULT_POSTGRES_PORT: u16 = 5432;
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "DbSyncNetwork",
+    group = "demeter.run",
+    version = "v1alpha1",
+    shortname = "dbsn"
+)]
+#[kube(printcolumn = r#"
+        {"name": "Database", "jsonPath": ".spec.databaseName", "type": "string"},
+        {"name": "Aliases", "jsonPath": ".spec.aliases", "type": "string"},
+        {"name": "Public Host", "jsonPath": ".spec.publicHost", "type": "string"}
+    "#)]
+#[serde(rename_all = "camelCase")]
+pub struct DbSyncNetworkSpec {
+    /// Other names the ports can use for the network, the name of the object is the canonical one
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub database_name: String,
+    /// Postgres instances serving the database, as host:port
+    pub endpoints: Vec<String>,
+    /// Secret with the username and password the operator connects with
+    pub credentials_secret_ref: NetworkSecretRef,
+    /// Host the users of the ports connect to
+    pub public_host: Option<String>,
+}
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct NetworkSecretRef {
+    pub name: String,
+    pub namespace: String,
+}
+
+#[derive(Clone)]
+struct Entry {
+    aliases: Vec<String>,
+    public_host: Option<String>,
+    // One per endpoint, the pools are reused while they don't change
+    urls: Vec<String>,
+    connections: Vec<Postgres>,
+}
+
+// The networks the operator serves, by canonical name. The ones of DB_URLS and DB_NAMES are
+// always there, a DbSyncNetwork with the same name replaces them.
+#[derive(Default)]
+pub struct Networks {
+    configured: HashMap<String, Entry>,
+    entries: RwLock<HashMap<String, Entry>>,
+    // Networks whose last build failed, so the event is only published when it starts failing
+    failing: RwLock<HashSet<String>>,
+}
+
+impl Networks {
+    pub async fn from_config(config: &Config) -> Result<Self, Error> {
+        let mut configured = HashMap::new();
+        for (network, db_name) in config.db_names.iter() {
+            let mut urls = Vec::new();
+            let mut connections = Vec::new();
+            for url in config.db_urls.iter() {
+                let url = format!("{url}/{db_name}");
+                connections.push(Postgres::try_new(&url, &config.db_max_connections).await?);
+                urls.push(url);
+            }
+
+            let entry = Entry {
+                aliases: legacy_aliases(network),
+                public_host: None,
+                urls,
+                connections,
+            };
+            configured.insert(network.clone(), entry);
+        }
+
+        Ok(Self {
+            entries: RwLock::new(configured.clone()),
+            configured,
+            failing: Default::default(),
+        })
+    }
+
+    // The canonical name of a network, matched by name first so an alias can't shadow a network
+    pub fn resolve(&self, network: &str) -> Option<String> {
+        let entries = self.entries.read().unwrap();
+        if entries.contains_key(network) {
+            return Some(network.to_string());
+        }
+
+        let mut names: Vec<&String> = entries
+            .iter()
+            .filter(|(_, entry)| entry.aliases.iter().any(|alias| alias == network))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names.first().map(|name| name.to_string())
+    }
+
+    // Names that aren't in the catalogue are kept, the reconcile reports them as unknown
+    pub fn canonical(&self, network: &str) -> String {
+        self.resolve(network).unwrap_or(network.to_string())
+    }
+
+    pub fn connections(&self, network: &str) -> Option<Vec<Postgres>> {
+        let name = self.resolve(network)?;
+        let entries = self.entries.read().unwrap();
+        entries.get(&name).map(|entry| entry.connections.clone())
+    }
+
+    pub fn public_host(&self, network: &str) -> Option<String> {
+        let name = self.resolve(network)?;
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(&name)
+            .and_then(|entry| entry.public_host.clone())
+    }
+
+    // The pools of every network, taken at once so a loop over them isn't holding the lock
+    pub fn snapshot(&self) -> BTreeMap<String, Vec<Postgres>> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.connections.clone()))
+            .collect()
+    }
+
+    pub fn close(&self) {
+        let entries = self.entries.read().unwrap();
+        for pg in entries.values().flat_map(|entry| entry.connections.iter()) {
+            pg.close();
+        }
+    }
+
+    // Builds the catalogue again from the DbSyncNetwork objects. A network that can't be built
+    // keeps the pools it had, so a Secret that is briefly missing doesn't stop its ports. A new
+    // network is served by the endpoints that could be built and is degraded until the others are.
+    pub async fn update(
+        &self,
+        client: &Client,
+        max_connections: &usize,
+        networks: &[DbSyncNetwork],
+    ) -> Vec<(DbSyncNetwork, Error)> {
+        let previous = self.entries.read().unwrap().clone();
+        let mut pools: HashMap<String, Postgres> = previous
+            .values()
+            .flat_map(|entry| entry.urls.iter().cloned().zip(entry.connections.clone()))
+            .collect();
+
+        let mut entries = self.configured.clone();
+        let mut failures = Vec::new();
+        for network in networks {
+            let name = network.name_any();
+            let urls = match connection_urls(client, network).await {
+                Ok(urls) => urls,
+                Err(err) => {
+                    if let Some(entry) = previous.get(&name) {
+                        entries.insert(name, entry.clone());
+                    }
+                    failures.push((network.clone(), err));
+                    continue;
+                }
+            };
+
+            let mut entry = Entry {
+                aliases: network.spec.aliases.clone(),
+                public_host: network.spec.public_host.clone(),
+                urls: Vec::new(),
+                connections: Vec::new(),
+            };
+            let mut failed = None;
+            for url in urls {
+                let pg = match pools.get(&url) {
+                    Some(pg) => pg.clone(),
+                    None => match Postgres::try_new(&url, max_connections).await {
+                        Ok(pg) => pg,
+                        Err(err) => {
+                            failed = Some(err);
+                            continue;
+                        }
+                    },
+                };
+                pools.insert(url.clone(), pg.clone());
+                entry.urls.push(url);
+                entry.connections.push(pg);
+            }
+
+            // Dropping an endpoint would let the ports look provisioned without it
+            match (failed, previous.get(&name)) {
+                (Some(err), Some(previous)) => {
+                    entries.insert(name, previous.clone());
+                    failures.push((network.clone(), err));
+                }
+                (Some(err), None) => {
+                    entries.insert(name, entry);
+                    failures.push((network.clone(), err));
+                }
+                (None, _) => {
+                    entries.insert(name, entry);
+                }
+            }
+        }
+
+        let kept: HashSet<&String> = entries.values().flat_map(|entry| &entry.urls).collect();
+        for (url, pg) in pools.iter() {
+            if !kept.contains(url) {
+                pg.close();
+            }
+        }
+
+        let mut added: Vec<&String> = entries
+            .keys()
+            .filter(|name| !previous.contains_key(*name))
+            .collect();
+        added.sort();
+        if !added.is_empty() {
+            info!(networks = ?added, "networks added");
+        }
+        *self.entries.write().unwrap() = entries;
+
+        failures
+    }
+
+    // A network whose DbSyncNetwork couldn't be built, it's served by the pools it had or by
+    // part of its endpoints
+    pub fn is_degraded(&self, network: &str) -> bool {
+        let name = self.canonical(network);
+        self.failing.read().unwrap().contains(&name)
+    }
+
+    // True when the network wasn't failing already
+    fn start_failing(&self, network: &str) -> bool {
+        self.failing.write().unwrap().insert(network.to_string())
+    }
+
+    fn stop_failing(&self, networks: &[DbSyncNetwork], failures: &[(DbSyncNetwork, Error)]) {
+        let failed: HashSet<String> = failures.iter().map(|(n, _)| n.name_any()).collect();
+        let names: HashSet<String> = networks.iter().map(|n| n.name_any()).collect();
+        self.failing
+            .write()
+            .unwrap()
+            .retain(|name| failed.contains(name) && names.contains(name));
+    }
+}
+
+// The connection strings are built as key/value pairs, so the credentials don't need to be
+// escaped as an url
+fn connection_url(endpoint: &str, database: &str, username: &str, password: &str) -> String {
+    let (host, port) = match endpoint.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => (host, port.to_string()),
+        _ => (endpoint, DEFAULT_POSTGRES_PORT.to_string()),
+    };
+    let quote = |value: &str| format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"));
+
+    format!(
+        "host={} port={port} dbname={} user={} password={}",
+        quote(host),
+        quote(database),
+        quote(username),
+        quote(password)
+    )
+}
+
+async fn connection_urls(client: &Client, network: &DbSyncNetwork) -> Result<Vec<String>, Error> {
+    let secret_ref = &network.spec.credentials_secret_ref;
+    let secret = Api::<Secret>::namespaced(client.clone(), &secret_ref.namespace)
+        .get(&secret_ref.name)
+        .await?;
+    let data = secret.data.unwrap_or_default();
+
+    let read = |key: &str| {
+        data.get(key)
+            .map(|value| String::from_utf8_lossy(&value.0).to_string())
+            .ok_or(Error::ConfigError(format!(
+                "secret {}/{} doesn't have a {key}",
+                secret_ref.namespace, secret_ref.name
+            )))
+    };
+    let username = read("username")?;
+    let password = read("password")?;
+
+    Ok(network
+        .spec
+        .endpoints
+        .iter()
+        .map(|endpoint| connection_url(endpoint, &network.spec.database_name, &username, &password))
+        .collect())
+}
+
+async fn refresh(state: &State, networks: &BTreeMap<String, DbSyncNetwork>) {
+    let config = crate::get_config();
+    let networks: Vec<DbSyncNetwork> = networks.values().cloned().collect();
+    let failures = state
+        .networks
+        .update(&state.kube_client, &config.db_max_connections, &networks)
+        .await;
+
+    for (network, err) in failures.iter() {
+        let name = network.name_any();
+        warn!(
+            network = name,
+            error = err.to_string(),
+            "fail to build network"
+        );
+
+        // Every replica builds the catalogue, only the leader reports it
+        if state.networks.start_failing(&name) && state.leadership.is_leader() {
+            let note = format!("network {name} can't be served: {err}");
+            events::publish(
+                state,
+                network,
+                EventType::Warning,
+                events::NETWORK_UNAVAILABLE,
+                "Build",
+                note,
+            )
+            .await;
+        }
+    }
+    state.networks.stop_failing(&networks, &failures);
+}
+
+// Runs on every replica, the pools of each replica follow the DbSyncNetwork objects
+#[instrument("network watcher", skip_all)]
+pub async fn run_network_watcher(state: Arc<State>) {
+    let api = Api::<DbSyncNetwork>::all(state.kube_client.clone());
+    let mut stream = watcher(api, watcher::Config::default())
+        .default_backoff()
+        .boxed();
+    let mut networks: BTreeMap<String, DbSyncNetwork> = BTreeMap::new();
+    let mut interval = tokio::time::interval(NETWORK_REFRESH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            event = stream.next() => match event {
+                Some(Ok(watcher::Event::Applied(network))) => {
+                    networks.insert(network.name_any(), network);
+                }
+                Some(Ok(watcher::Event::Deleted(network))) => {
+                    networks.remove(&network.name_any());
+                }
+                Some(Ok(watcher::Event::Restarted(list))) => {
+                    networks = list.into_iter().map(|n| (n.name_any(), n)).collect();
+                }
+                Some(Err(err)) => {
+                    warn!(error = err.to_string(), "network watcher failed");
+                    continue;
+                }
+                None => return,
+            },
+            _ = interval.tick() => {},
+            _ = state.shutdown.wait() => return,
+        }
+
+        refresh(&state, &networks).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(aliases: &[&str]) -> Entry {
+        Entry {
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            public_host: None,
+            urls: Vec::new(),
+            connections: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let networks = Networks::default();
+        *networks.entries.write().unwrap() = HashMap::from([
+            ("cardano-mainnet".to_string(), entry(&["mainnet"])),
+            ("mainnet".to_string(), entry(&[])),
+            ("vector-testnet".to_string(), entry(&["vector", "testnet"])),
+        ]);
+
+        assert_eq!(
+            networks.resolve("vector").as_deref(),
+            Some("vector-testnet")
+        );
+        assert_eq!(networks.resolve("mainnet").as_deref(), Some("mainnet"));
+        assert!(networks.resolve("preprod").is_none());
+        assert_eq!(networks.canonical("preprod"), "preprod");
+    }
+
+    #[test]
+    fn test_is_degraded() {
+        let networks = Networks::default();
+        *networks.entries.write().unwrap() = HashMap::from([
+            ("vector-testnet".to_string(), entry(&["vector"])),
+            ("cardano-preprod".to_string(), entry(&["preprod"])),
+        ]);
+
+        assert!(networks.start_failing("vector-testnet"));
+        assert!(!networks.start_failing("vector-testnet"));
+        assert!(networks.is_degraded("vector"));
+        assert!(networks.is_degraded("vector-testnet"));
+        assert!(!networks.is_degraded("preprod"));
+    }
+
+    #[test]
+    fn test_connection_url() {
+        use std::str::FromStr;
+
+        let url = connection_url("10.0.0.1:5433", "dbsync-mainnet", "user", "pa'ss");
+        assert_eq!(
+            url,
+            r"host='10.0.0.1' port=5433 dbname='dbsync-mainnet' user='user' password='pa\'ss'"
+        );
+
+        let url = connection_url("postgres.dbsync", "dbsync", "user", "password");
+        assert!(url.starts_with("host='postgres.dbsync' port=5432 "));
+
+        let config = tokio_postgres::Config::from_str(&url).unwrap();
+        assert_eq!(config.get_dbname(), Some("dbsync"));
+
+        let url = connection_url("postgres", "dbsync", "user", r"pa'ss\word");
+        let config = tokio_postgres::Config::from_str(&url).unwrap();
+        assert_eq!(config.get_password(), Some(r"pa'ss\word".as_bytes()));
+    }
+}
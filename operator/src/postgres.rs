@@ -1,12 +1,93 @@
-use std::str::FromStr;
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
-use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
-use tokio_postgres::NoTls;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Status};
+use tokio_postgres::{config::Host, error::SqlState, NoTls};
+use tracing::instrument;
 
 use crate::{get_config, Error};
 
+#[derive(Debug, Clone, Default)]
+pub struct RoleStats {
+    pub connections: i64,
+    pub active_queries: i64,
+    pub longest_query_seconds: f64,
+    pub total_exec_time_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SchemaObjects {
+    pub pg_stat_statements: bool,
+    pub pgbouncer: bool,
+    pub user_search: bool,
+    pub tables: HashSet<String>,
+    pub valid_indexes: HashSet<String>,
+    pub invalid_indexes: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provision {
+    Created,
+    DriftFixed,
+    Unchanged,
+}
+
+impl Provision {
+    // The most significant change of several roles provisioned on an instance
+    pub fn merge(self, other: Provision) -> Provision {
+        match (self, other) {
+            (Provision::Created, _) | (_, Provision::Created) => Provision::Created,
+            (Provision::DriftFixed, _) | (_, Provision::DriftFixed) => Provision::DriftFixed,
+            _ => Provision::Unchanged,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Port,
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Grants {
+    pub access: Access,
+    pub statement_timeout: u64,
+}
+
+impl Grants {
+    pub fn port() -> Self {
+        Self {
+            access: Access::Port,
+            statement_timeout: get_config().statement_timeout,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub username: String,
+    pub password: String,
+    /// RFC 3339 timestamp after which the password is rejected
+    pub valid_until: Option<String>,
+}
+
+// A missing extension shows up as an undefined table or function, other errors are real failures
+fn is_undefined(err: &tokio_postgres::Error) -> bool {
+    matches!(
+        err.code(),
+        Some(&SqlState::UNDEFINED_TABLE) | Some(&SqlState::UNDEFINED_FUNCTION)
+    )
+}
+
 #[derive(Clone)]
 pub struct Postgres {
+    pub instance: String,
+    database: String,
+    config: tokio_postgres::Config,
     pool: Pool,
 }
 
@@ -18,98 +99,593 @@ impl Postgres {
 
         let config = tokio_postgres::Config::from_str(url)?;
 
-        let mgr = Manager::from_config(config, NoTls, mgr_config);
+        let host = match config.get_hosts().first() {
+            Some(Host::Tcp(host)) => host.clone(),
+            #[cfg(unix)]
+            Some(Host::Unix(path)) => path.display().to_string(),
+            None => "localhost".into(),
+        };
+        let port = config.get_ports().first().copied().unwrap_or(5432);
+        let instance = format!("{host}:{port}");
+        let database = config.get_dbname().unwrap_or("postgres").to_string();
+
+        let mgr = Manager::from_config(config.clone(), NoTls, mgr_config);
         let pool = Pool::builder(mgr).max_size(*max_size).build()?;
 
-        Ok(Self { pool })
+        Ok(Self {
+            instance,
+            database,
+            config,
+            pool,
+        })
+    }
+
+    pub fn status(&self) -> Status {
+        self.pool.status()
     }
 
-    pub async fn create_user(&self, username: &str, password: &str) -> Result<(), Error> {
+    pub fn close(&self) {
+        self.pool.close();
+    }
+
+    #[instrument("pg create user", skip_all, fields(instance = %self.instance, username = %username))]
+    pub async fn create_user(
+        &self,
+        username: &str,
+        password: &str,
+        grants: &Grants,
+        force_grants: bool,
+    ) -> Result<Provision, Error> {
+        let grant_queries = grant_queries(username, &self.database, grants);
+
         if self.user_exist(username).await? {
-            return Ok(());
+            let timeout = Some(grants.statement_timeout);
+            if !force_grants && !self.grants_drifted(username, grants, timeout).await? {
+                return Ok(Provision::Unchanged);
+            }
+
+            self.execute_in_transaction(&grant_queries).await?;
+            return Ok(Provision::DriftFixed);
         }
 
-        let query_create_user = format!("create user \"{username}\" with password '{password}';");
-        let query_grant = format!("grant select on all tables in schema public to \"{username}\";");
-        let query_privileges = format!("alter default privileges in schema public grant all privileges on tables to \"{username}\";");
+        let query_create_user = format!(
+            "create user {} with password {};",
+            quote_ident(username),
+            quote_literal(password)
+        );
 
-        let timeout = get_config().statement_timeout;
-        let query_set_timeout =
-            format!("alter role \"{username}\" set statement_timeout = '{timeout}';");
+        let mut queries = vec![query_create_user];
+        queries.extend(grant_queries);
 
-        let mut client = self.pool.get().await?;
-        let tx = client.transaction().await?;
+        self.execute_in_transaction(&queries).await?;
+        Ok(Provision::Created)
+    }
 
-        let user_stmt = tx.prepare(&query_create_user).await?;
-        let user_result = tx.execute(&user_stmt, &[]).await;
-        if let Err(err) = user_result {
-            tx.rollback().await?;
-            return Err(Error::PgError(err.to_string()));
-        }
+    // The grants and the open sessions are kept. The password is set again, since an md5 password
+    // is salted with the role name and renaming clears it.
+    #[instrument("pg rename user", skip_all, fields(instance = %self.instance, username = %username))]
+    pub async fn rename_user(
+        &self,
+        username: &str,
+        new_username: &str,
+        password: &str,
+    ) -> Result<(), Error> {
+        let (role, new_role) = (quote_ident(username), quote_ident(new_username));
+        let query_rename = format!("alter role {role} rename to {new_role};");
+        let query_password = format!(
+            "alter role {new_role} with password {};",
+            quote_literal(password)
+        );
+
+        self.execute_in_transaction(&[query_rename, query_password])
+            .await
+    }
 
-        let grant_stmt = tx.prepare(&query_grant).await?;
-        let grant_result = tx.execute(&grant_stmt, &[]).await;
-        if let Err(err) = grant_result {
-            tx.rollback().await?;
-            return Err(Error::PgError(err.to_string()));
+    #[instrument("pg set password", skip_all, fields(instance = %self.instance, username = %username))]
+    pub async fn set_password(&self, username: &str, password: &str) -> Result<(), Error> {
+        if !self.user_exist(username).await? {
+            return Ok(());
         }
 
-        let privileges_stmt = tx.prepare(&query_privileges).await?;
-        if let Err(err) = tx.execute(&privileges_stmt, &[]).await {
-            tx.rollback().await?;
-            return Err(Error::PgError(err.to_string()));
+        let query = format!(
+            "alter role {} with password {};",
+            quote_ident(username),
+            quote_literal(password)
+        );
+        self.execute_in_transaction(&[query]).await
+    }
+
+    // Group roles can't log in, they hold the grants shared by the credentials of a port
+    #[instrument("pg create group", skip_all, fields(instance = %self.instance, group = %group))]
+    pub async fn create_group(
+        &self,
+        group: &str,
+        grants: &Grants,
+        force_grants: bool,
+    ) -> Result<Provision, Error> {
+        let privilege_queries = privilege_queries(group, &self.database, grants);
+
+        if self.user_exist(group).await? {
+            if !force_grants && !self.grants_drifted(group, grants, None).await? {
+                return Ok(Provision::Unchanged);
+            }
+
+            self.execute_in_transaction(&privilege_queries).await?;
+            return Ok(Provision::DriftFixed);
         }
 
-        let set_timeout_stmt = tx.prepare(&query_set_timeout).await?;
-        let set_timeout_result = tx.execute(&set_timeout_stmt, &[]).await;
-        if let Err(err) = set_timeout_result {
-            tx.rollback().await?;
-            return Err(Error::PgError(err.to_string()));
+        let mut queries = vec![format!("create role {} nologin;", quote_ident(group))];
+        queries.extend(privilege_queries);
+
+        self.execute_in_transaction(&queries).await?;
+        Ok(Provision::Created)
+    }
+
+    // A login role that inherits the grants of the group. Settings aren't inherited, so the
+    // statement timeout is set on each member.
+    #[instrument("pg create member", skip_all, fields(instance = %self.instance, username = %member.username))]
+    pub async fn create_member(
+        &self,
+        member: &Member,
+        group: &str,
+        statement_timeout: u64,
+        force_grants: bool,
+    ) -> Result<Provision, Error> {
+        let username = &member.username;
+        let valid_until = member.valid_until.as_deref().unwrap_or("infinity");
+        let (role, group_role) = (quote_ident(username), quote_ident(group));
+        let member_queries = vec![
+            format!(
+                "alter role {role} valid until {};",
+                quote_literal(valid_until)
+            ),
+            format!("alter role {role} set statement_timeout = '{statement_timeout}';"),
+            format!("grant {group_role} to {role};"),
+        ];
+
+        if self.user_exist(username).await? {
+            if !force_grants
+                && !self
+                    .member_drifted(username, group, statement_timeout, valid_until)
+                    .await?
+            {
+                return Ok(Provision::Unchanged);
+            }
+
+            self.execute_in_transaction(&member_queries).await?;
+            return Ok(Provision::DriftFixed);
         }
 
-        tx.commit().await?;
-        Ok(())
+        let password = quote_literal(&member.password);
+        let mut queries = vec![format!("create user {role} with password {password};")];
+        queries.extend(member_queries);
+
+        self.execute_in_transaction(&queries).await?;
+        Ok(Provision::Created)
     }
 
+    async fn member_drifted(
+        &self,
+        username: &str,
+        group: &str,
+        statement_timeout: u64,
+        valid_until: &str,
+    ) -> Result<bool, Error> {
+        let query = "
+            select
+                not pg_has_role($1::name, $2::name, 'member')
+                or not exists (
+                    select 1 from pg_roles
+                    where rolname = $1::name
+                        and coalesce(rolconfig, '{}') @> array[$3::text]
+                        and rolvaliduntil is not distinct from $4::text::timestamptz
+                );
+        ";
+        let timeout = format!("statement_timeout={statement_timeout}");
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query).await?;
+        let result = client
+            .query_one(&stmt, &[&username, &group, &timeout, &valid_until])
+            .await?;
+
+        Ok(result.get(0))
+    }
+
+    #[instrument("pg drop user", skip_all, fields(instance = %self.instance, username = %username))]
     pub async fn drop_user(&self, username: &str) -> Result<(), Error> {
         if !self.user_exist(username).await? {
             return Ok(());
         }
 
-        let query_reassign = format!("reassign owned by \"{username}\" to postgres;");
-        let query_revoke = format!("drop owned by \"{username}\";");
-        let query_drop_user = format!("drop user \"{username}\";");
+        let role = quote_ident(username);
+        let query_reassign = format!("reassign owned by {role} to postgres;");
+        let query_revoke = format!("drop owned by {role};");
+        let query_drop_user = format!("drop user {role};");
+
+        self.execute_in_transaction(&[query_reassign, query_revoke, query_drop_user])
+            .await
+    }
+
+    // Roles are shared by all databases of an instance, but privileges and owned objects are per
+    // database, so they have to be dropped on each database before the role can be dropped.
+    #[instrument("pg drop owned", skip_all, fields(instance = %self.instance, username = %username))]
+    pub async fn drop_owned(&self, username: &str) -> Result<(), Error> {
+        if !self.user_exist(username).await? {
+            return Ok(());
+        }
+
+        let role = quote_ident(username);
+        let query_reassign = format!("reassign owned by {role} to postgres;");
+        let query_revoke = format!("drop owned by {role};");
+
+        self.execute_in_transaction(&[query_reassign, query_revoke])
+            .await
+    }
+
+    #[instrument("pg active sessions", skip_all, fields(instance = %self.instance, username = %username))]
+    pub async fn active_sessions(&self, username: &str) -> Result<i64, Error> {
+        let query = "select count(*) from pg_stat_activity where usename = $1;";
+
+        let client = self.pool.get().await?;
 
+        let stmt = client.prepare(query).await?;
+        let result = client.query_one(&stmt, &[&username]).await?;
+
+        Ok(result.get(0))
+    }
+
+    // Disables the login before terminating the sessions, so clients can't reconnect before the drop
+    #[instrument("pg terminate sessions", skip_all, fields(instance = %self.instance, username = %username))]
+    pub async fn terminate_sessions(&self, username: &str) -> Result<(), Error> {
+        let query_nologin = format!("alter role {} nologin;", quote_ident(username));
+        let query_terminate =
+            "select pg_terminate_backend(pid) from pg_stat_activity where usename = $1;";
+
+        let client = self.pool.get().await?;
+
+        let nologin_stmt = client.prepare(&query_nologin).await?;
+        client.execute(&nologin_stmt, &[]).await?;
+
+        let terminate_stmt = client.prepare(query_terminate).await?;
+        client.query(&terminate_stmt, &[&username]).await?;
+
+        Ok(())
+    }
+
+    async fn grants_drifted(
+        &self,
+        username: &str,
+        grants: &Grants,
+        statement_timeout: Option<u64>,
+    ) -> Result<bool, Error> {
+        let query = "
+            select
+                exists (
+                    select 1 from pg_tables
+                    where schemaname = 'public'
+                        and not has_table_privilege($1::name, format('%I.%I', schemaname, tablename), 'select')
+                )
+                or ($2::text is not null and not exists (
+                    select 1 from pg_roles
+                    where rolname = $1::name and coalesce(rolconfig, '{}') @> array[$2::text]
+                ))
+                or ($3 and not has_schema_privilege($1::name, 'public', 'create'));
+        ";
+        let timeout = statement_timeout.map(|timeout| format!("statement_timeout={timeout}"));
+        let write = grants.access == Access::Write;
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query).await?;
+        let result = client
+            .query_one(&stmt, &[&username, &timeout, &write])
+            .await?;
+
+        Ok(result.get(0))
+    }
+
+    async fn execute_in_transaction(&self, queries: &[String]) -> Result<(), Error> {
         let mut client = self.pool.get().await?;
         let tx = client.transaction().await?;
 
-        let reassign_stmt = tx.prepare(&query_reassign).await?;
-        let reassign_result = tx.execute(&reassign_stmt, &[]).await;
-        if let Err(err) = reassign_result {
-            tx.rollback().await?;
-            return Err(Error::PgError(err.to_string()));
+        for query in queries {
+            let stmt = tx.prepare(query).await?;
+            if let Err(err) = tx.execute(&stmt, &[]).await {
+                tx.rollback().await?;
+                return Err(Error::PgError(err.to_string()));
+            }
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[instrument("pg count roles", skip_all, fields(instance = %self.instance))]
+    pub async fn count_roles(&self) -> Result<i64, Error> {
+        let query = "select count(*) from pg_roles where rolcanlogin and not rolsuper;";
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query).await?;
+        let result = client.query_one(&stmt, &[]).await?;
+
+        Ok(result.get(0))
+    }
+
+    #[instrument("pg role stats", skip_all, fields(instance = %self.instance))]
+    pub async fn role_stats(
+        &self,
+        usernames: &[String],
+    ) -> Result<HashMap<String, RoleStats>, Error> {
+        let query_activity = "
+            select
+                usename::text,
+                count(*),
+                count(*) filter (where state = 'active'),
+                coalesce(extract(epoch from max(now() - query_start) filter (where state = 'active')), 0)::float8
+            from pg_stat_activity
+            where datname = current_database() and usename = any($1)
+            group by usename;
+        ";
+        let query_statements = "
+            select r.rolname::text, coalesce(sum(s.total_exec_time), 0)::float8
+            from pg_stat_statements s
+            join pg_roles r on r.oid = s.userid
+            where s.dbid = (select oid from pg_database where datname = current_database())
+                and r.rolname = any($1)
+            group by r.rolname;
+        ";
+
+        let client = self.pool.get().await?;
+
+        let mut stats: HashMap<String, RoleStats> = usernames
+            .iter()
+            .map(|username| (username.clone(), RoleStats::default()))
+            .collect();
+
+        let stmt = client.prepare(query_activity).await?;
+        for row in client.query(&stmt, &[&usernames]).await? {
+            if let Some(role) = stats.get_mut(row.get::<_, &str>(0)) {
+                role.connections = row.get(1);
+                role.active_queries = row.get(2);
+                role.longest_query_seconds = row.get(3);
+            }
         }
 
-        let revoke_stmt = tx.prepare(&query_revoke).await?;
-        let revoke_result = tx.execute(&revoke_stmt, &[]).await;
-        if let Err(err) = revoke_result {
-            tx.rollback().await?;
-            return Err(Error::PgError(err.to_string()));
+        // pg_stat_statements is optional, without it only the activity is reported
+        let rows = match client.prepare(query_statements).await {
+            Ok(stmt) => client.query(&stmt, &[&usernames]).await?,
+            Err(err) if is_undefined(&err) => return Ok(stats),
+            Err(err) => return Err(err.into()),
+        };
+        for role in stats.values_mut() {
+            role.total_exec_time_ms = Some(0.);
+        }
+        for row in rows {
+            if let Some(role) = stats.get_mut(row.get::<_, &str>(0)) {
+                role.total_exec_time_ms = Some(row.get(1));
+            }
         }
 
-        let drop_user_stmt = tx.prepare(&query_drop_user).await?;
-        let drop_user_result = tx.execute(&drop_user_stmt, &[]).await;
-        if let Err(err) = drop_user_result {
-            tx.rollback().await?;
-            return Err(Error::PgError(err.to_string()));
+        Ok(stats)
+    }
+
+    // The login roles and the group roles of the ports, which can't log in
+    #[instrument("pg list roles", skip_all, fields(instance = %self.instance))]
+    pub async fn list_roles(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let query =
+            "select rolname::text, rolcanlogin from pg_roles where not rolsuper order by rolname;";
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query).await?;
+        let result = client.query(&stmt, &[]).await?;
+
+        let roles = result.iter().map(|row| (row.get(0), row.get(1))).collect();
+        Ok(managed_roles(roles, prefix))
+    }
+
+    // dbsync creates its schema on the first run, until then there is nothing to prepare
+    #[instrument("pg schema created", skip_all, fields(instance = %self.instance))]
+    pub async fn schema_created(&self) -> Result<bool, Error> {
+        let query = "select to_regclass('public.block') is not null;";
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query).await?;
+        let result = client.query_one(&stmt, &[]).await?;
+
+        Ok(result.get(0))
+    }
+
+    // Standbys and instances set to read-only can't create roles
+    #[instrument("pg read only", skip_all, fields(instance = %self.instance))]
+    pub async fn read_only(&self) -> Result<bool, Error> {
+        let query =
+            "select pg_is_in_recovery() or current_setting('default_transaction_read_only') = 'on';";
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query).await?;
+        let result = client.query_one(&stmt, &[]).await?;
+
+        Ok(result.get(0))
+    }
+
+    // Slot and unix time of the latest block dbsync has written
+    #[instrument("pg tip", skip_all, fields(instance = %self.instance))]
+    pub async fn tip(&self) -> Result<Option<(i64, f64)>, Error> {
+        if !self.schema_created().await? {
+            return Ok(None);
         }
 
-        tx.commit().await?;
+        let query = "
+            select slot_no::bigint, extract(epoch from time)::float8
+            from block
+            where slot_no is not null
+            order by id desc
+            limit 1;
+        ";
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query).await?;
+        let result = client.query_opt(&stmt, &[]).await?;
+
+        Ok(result.map(|row| (row.get(0), row.get(1))))
+    }
+
+    #[instrument("pg schema objects", skip_all, fields(instance = %self.instance))]
+    pub async fn schema_objects(&self) -> Result<SchemaObjects, Error> {
+        let query_prerequisites = "
+            select
+                exists (select 1 from pg_extension where extname = 'pg_stat_statements'),
+                exists (select 1 from pg_roles where rolname = 'pgbouncer' and rolcanlogin),
+                exists (
+                    select 1 from pg_proc p
+                    join pg_namespace n on n.oid = p.pronamespace
+                    where n.nspname = 'public' and p.proname = 'user_search'
+                );
+        ";
+        let query_tables = "select tablename::text from pg_tables where schemaname = 'public';";
+        let query_indexes = "
+            select c.relname::text, i.indisvalid
+            from pg_index i
+            join pg_class c on c.oid = i.indexrelid
+            join pg_namespace n on n.oid = c.relnamespace
+            where n.nspname = 'public';
+        ";
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query_prerequisites).await?;
+        let result = client.query_one(&stmt, &[]).await?;
+
+        let mut objects = SchemaObjects {
+            pg_stat_statements: result.get(0),
+            pgbouncer: result.get(1),
+            user_search: result.get(2),
+            ..Default::default()
+        };
+
+        let stmt = client.prepare(query_tables).await?;
+        for row in client.query(&stmt, &[]).await? {
+            objects.tables.insert(row.get(0));
+        }
+
+        let stmt = client.prepare(query_indexes).await?;
+        for row in client.query(&stmt, &[]).await? {
+            let name: String = row.get(0);
+            match row.get(1) {
+                true => objects.valid_indexes.insert(name),
+                false => objects.invalid_indexes.insert(name),
+            };
+        }
+
+        Ok(objects)
+    }
+
+    #[instrument("pg create extension", skip_all, fields(instance = %self.instance))]
+    pub async fn create_pg_stat_statements(&self) -> Result<(), Error> {
+        let query = "create extension if not exists pg_stat_statements;".to_string();
+        self.execute_in_transaction(&[query]).await
+    }
+
+    // The role is shared by the databases of the instance, the first database prepared creates it
+    #[instrument("pg create pgbouncer role", skip_all, fields(instance = %self.instance))]
+    pub async fn create_pgbouncer_role(&self, password: &str) -> Result<(), Error> {
+        let statement = match self.user_exist("pgbouncer").await? {
+            true => "alter role pgbouncer with login password",
+            false => "create role pgbouncer with login password",
+        };
+        let query = format!("{statement} {};", quote_literal(password));
+        self.execute_in_transaction(&[query]).await
+    }
+
+    // pgbouncer's auth_query, reads the password of the clients from pg_shadow
+    #[instrument("pg create user search", skip_all, fields(instance = %self.instance))]
+    pub async fn create_user_search(&self) -> Result<(), Error> {
+        let query = "
+            create or replace function user_search(uname text) returns table (usename name, passwd text) as
+            $$
+              select usename, passwd from pg_shadow where usename = $1;
+            $$
+            language sql security definer;
+        "
+        .to_string();
+        self.execute_in_transaction(&[query]).await
+    }
+
+    // Index builds and analyze run for a long time, they use a connection of their own so the
+    // pool stays available to the controller and the collector
+    async fn execute_dedicated(&self, query: &str) -> Result<(), Error> {
+        let (client, connection) = self.config.connect(NoTls).await?;
+        let connection = tokio::spawn(connection);
+
+        let result = client.batch_execute(query).await;
+        drop(client);
+        let _ = connection.await;
+        result?;
         Ok(())
     }
 
-    async fn user_exist(&self, username: &str) -> Result<bool, Error> {
-        let query = "select usesysid from pg_user where pg_user.usename = $1;";
+    // Concurrent builds can't run in a transaction and keep the tables writable for dbsync. A
+    // build that is interrupted leaves an invalid index, which has to be dropped and built again.
+    #[instrument("pg create index", skip_all, fields(instance = %self.instance, index = %name))]
+    pub async fn create_index_concurrently(
+        &self,
+        name: &str,
+        statement: &str,
+    ) -> Result<(), Error> {
+        self.execute_dedicated(statement).await
+    }
+
+    #[instrument("pg drop index", skip_all, fields(instance = %self.instance, index = %name))]
+    pub async fn drop_index_concurrently(&self, name: &str) -> Result<(), Error> {
+        let query = format!(
+            "drop index concurrently if exists public.{};",
+            quote_ident(name)
+        );
+        self.execute_dedicated(&query).await
+    }
+
+    #[instrument("pg analyze", skip_all, fields(instance = %self.instance))]
+    pub async fn analyze(&self) -> Result<(), Error> {
+        self.execute_dedicated("analyze;").await
+    }
+
+    // Group roles are included, they can't log in but share the names with the users
+    pub async fn is_member(&self, username: &str, group: &str) -> Result<bool, Error> {
+        let query = "
+            select 1 from pg_auth_members m
+            join pg_roles r on r.oid = m.member
+            join pg_roles g on g.oid = m.roleid
+            where r.rolname = $1 and g.rolname = $2;
+        ";
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query).await?;
+        let result = client.query_opt(&stmt, &[&username, &group]).await?;
+
+        Ok(result.is_some())
+    }
+
+    pub async fn is_superuser(&self, username: &str) -> Result<bool, Error> {
+        let query = "select rolsuper from pg_roles where rolname = $1;";
+
+        let client = self.pool.get().await?;
+
+        let stmt = client.prepare(query).await?;
+        let result = client.query_opt(&stmt, &[&username]).await?;
+
+        Ok(result.is_some_and(|row| row.get(0)))
+    }
+
+    pub async fn user_exist(&self, username: &str) -> Result<bool, Error> {
+        let query = "select oid from pg_roles where rolname = $1;";
 
         let client = self.pool.get().await?;
 
@@ -119,3 +695,112 @@ impl Postgres {
         Ok(result.is_some())
     }
 }
+
+fn managed_roles(roles: Vec<(String, bool)>, prefix: &str) -> Vec<String> {
+    roles
+        .into_iter()
+        .filter(|(role, can_login)| *can_login || role.starts_with(prefix))
+        .map(|(role, _)| role)
+        .collect()
+}
+
+// Port users keep the grants they always had. Internal users get the same grants the
+// grant_read_access.sh and grant_write_access.sh scripts used to apply.
+fn grant_queries(username: &str, database: &str, grants: &Grants) -> Vec<String> {
+    let timeout = grants.statement_timeout;
+    let mut queries = privilege_queries(username, database, grants);
+    queries.push(format!(
+        "alter role {} set statement_timeout = '{timeout}';",
+        quote_ident(username)
+    ));
+    queries
+}
+
+fn privilege_queries(username: &str, database: &str, grants: &Grants) -> Vec<String> {
+    let (role, database) = (quote_ident(username), quote_ident(database));
+    match grants.access {
+        Access::Port => vec![
+            format!("grant select on all tables in schema public to {role};"),
+            format!("alter default privileges in schema public grant all privileges on tables to {role};"),
+        ],
+        Access::Read => vec![
+            format!("grant connect on database {database} to {role};"),
+            format!("grant usage on schema public to {role};"),
+            format!("grant select on all tables in schema public to {role};"),
+            format!("alter default privileges in schema public grant select on tables to {role};"),
+        ],
+        Access::Write => vec![
+            format!("grant connect, create on database {database} to {role};"),
+            format!("grant usage, create on schema public to {role};"),
+            format!("grant select on all tables in schema public to {role};"),
+            format!("alter default privileges in schema public grant select on tables to {role};"),
+        ],
+    }
+}
+
+// Identifiers and literals can't be bound as parameters in these statements, so they are quoted
+// like quote_ident and quote_literal do in Postgres
+fn quote_ident(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+fn quote_literal(value: &str) -> String {
+    let escaped = value.replace('\'', "''");
+    match escaped.contains('\\') {
+        true => format!("E'{}'", escaped.replace('\\', "\\\\")),
+        false => format!("'{escaped}'"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote_ident("dmtr_dbsync1"), r#""dmtr_dbsync1""#);
+        assert_eq!(quote_ident(r#"a"; drop"#), r#""a""; drop""#);
+
+        assert_eq!(quote_literal("password"), "'password'");
+        assert_eq!(quote_literal("pa'ss"), "'pa''ss'");
+        assert_eq!(quote_literal(r"pa\'ss"), r"E'pa\\''ss'");
+    }
+
+    // The group of a deleted port can't log in, it's still listed so drop-orphans finds it
+    #[test]
+    fn test_managed_roles() {
+        let roles = vec![
+            ("dmtr_dbsync_a".to_string(), true),
+            ("dmtr_dbsync_a_group".to_string(), false),
+            ("dmtr_dbsync_c_group".to_string(), false),
+            ("dmtrro".to_string(), true),
+            ("pg_monitor".to_string(), false),
+        ];
+        assert_eq!(
+            managed_roles(roles, "dmtr_dbsync"),
+            vec![
+                "dmtr_dbsync_a",
+                "dmtr_dbsync_a_group",
+                "dmtr_dbsync_c_group",
+                "dmtrro"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_grant_queries() {
+        let grants = Grants {
+            access: Access::Read,
+            statement_timeout: 120000,
+        };
+        let queries = grant_queries("dmtr_dbsync1", "dbsync-mainnet", &grants);
+        assert_eq!(
+            queries[0],
+            r#"grant connect on database "dbsync-mainnet" to "dmtr_dbsync1";"#
+        );
+        assert_eq!(
+            queries.last().unwrap(),
+            r#"alter role "dmtr_dbsync1" set statement_timeout = '120000';"#
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use k8s_openapi::{api::core::v1::Namespace, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use kube::{Api, Client};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::Error;
+
+// Labels and annotations of a namespace rarely change, they are read again after this
+const NAMESPACE_CACHE_TTL: Duration = Duration::from_secs(300);
+
+// Where the project of a port comes from, tried in the order of PROJECT_SOURCES. The projectRef of
+// the spec comes last, whatever its place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProjectSource {
+    /// `spec.projectRef` of the port
+    Spec,
+    /// A label of the namespace of the port
+    Label(String),
+    /// An annotation of the namespace of the port
+    Annotation(String),
+    /// The rest of a namespace name that starts with the prefix
+    Prefix(String),
+}
+
+impl FromStr for ProjectSource {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "spec" => Ok(ProjectSource::Spec),
+            Some(("label", key)) if !key.is_empty() => Ok(ProjectSource::Label(key.into())),
+            Some(("annotation", key)) if !key.is_empty() => {
+                Ok(ProjectSource::Annotation(key.into()))
+            }
+            Some(("prefix", prefix)) if !prefix.is_empty() => {
+                Ok(ProjectSource::Prefix(prefix.into()))
+            }
+            _ => Err(Error::ConfigError(format!("invalid project source {s}"))),
+        }
+    }
+}
+
+pub fn parse_sources(sources: &str) -> Result<Vec<ProjectSource>, Error> {
+    sources.split(',').map(str::trim).map(str::parse).collect()
+}
+
+fn resolve(
+    sources: &[ProjectSource],
+    namespace: &str,
+    project_ref: Option<&str>,
+    metadata: Option<&ObjectMeta>,
+) -> Option<String> {
+    let from_metadata = |values: Option<&BTreeMap<String, String>>, key: &str| {
+        values
+            .and_then(|values| values.get(key))
+            .filter(|value| !value.is_empty())
+            .cloned()
+    };
+
+    // Anyone who can create a port sets its projectRef, so it can't bill another project than
+    // the one of the namespace. It only names the project of a namespace that doesn't have one.
+    let from_namespace = sources.iter().find_map(|source| match source {
+        ProjectSource::Spec => None,
+        ProjectSource::Label(key) => from_metadata(metadata?.labels.as_ref(), key),
+        ProjectSource::Annotation(key) => from_metadata(metadata?.annotations.as_ref(), key),
+        ProjectSource::Prefix(prefix) => namespace
+            .strip_prefix(prefix.as_str())
+            .filter(|project| !project.is_empty())
+            .map(String::from),
+    });
+    if from_namespace.is_some() {
+        return from_namespace;
+    }
+
+    match sources.contains(&ProjectSource::Spec) {
+        true => project_ref.filter(|p| !p.is_empty()).map(String::from),
+        false => None,
+    }
+}
+
+#[derive(Default)]
+pub struct Projects {
+    sources: Vec<ProjectSource>,
+    namespaces: RwLock<HashMap<String, (ObjectMeta, Instant)>>,
+    // When the metadata of each unresolved namespace was read as it was reported
+    unresolved: RwLock<HashMap<String, Option<Instant>>>,
+}
+
+impl Projects {
+    pub fn new(sources: Vec<ProjectSource>) -> Self {
+        Self {
+            sources,
+            namespaces: Default::default(),
+            unresolved: Default::default(),
+        }
+    }
+
+    // An unresolved namespace is reported once each time its metadata is read, not on every
+    // lookup of the cached one
+    pub fn report_unresolved(&self, namespace: &str) -> bool {
+        let read_at = self
+            .namespaces
+            .read()
+            .unwrap()
+            .get(namespace)
+            .map(|(_, read_at)| *read_at);
+        let mut unresolved = self.unresolved.write().unwrap();
+        unresolved.insert(namespace.into(), read_at) != Some(read_at)
+    }
+
+    fn uses_namespace(&self) -> bool {
+        self.sources.iter().any(|source| {
+            matches!(
+                source,
+                ProjectSource::Label(_) | ProjectSource::Annotation(_)
+            )
+        })
+    }
+
+    // Only uses the namespaces already cached, for the callers that can't wait for the API
+    pub fn resolve_cached(&self, namespace: &str, project_ref: Option<&str>) -> Option<String> {
+        let namespaces = self.namespaces.read().unwrap();
+        let metadata = namespaces.get(namespace).map(|(metadata, _)| metadata);
+        resolve(&self.sources, namespace, project_ref, metadata)
+    }
+
+    // A namespace that can't be read leaves its labels and annotations out, the other sources
+    // still apply.
+    pub async fn resolve(
+        &self,
+        client: &Client,
+        namespace: &str,
+        project_ref: Option<&str>,
+    ) -> Option<String> {
+        if self.uses_namespace() {
+            self.namespace(client, namespace).await;
+        }
+
+        self.resolve_cached(namespace, project_ref)
+    }
+
+    // The metadata of a namespace, read again once the cached one is stale
+    pub async fn namespace(&self, client: &Client, namespace: &str) -> Option<ObjectMeta> {
+        if self.is_stale(namespace) {
+            let api: Api<Namespace> = Api::all(client.clone());
+            match api.get(namespace).await {
+                Ok(ns) => {
+                    let mut namespaces = self.namespaces.write().unwrap();
+                    namespaces.insert(namespace.into(), (ns.metadata, Instant::now()));
+                }
+                Err(err) => warn!(error = err.to_string(), namespace, "fail to read namespace"),
+            }
+        }
+
+        let namespaces = self.namespaces.read().unwrap();
+        namespaces
+            .get(namespace)
+            .map(|(metadata, _)| metadata.clone())
+    }
+
+    fn is_stale(&self, namespace: &str) -> bool {
+        let namespaces = self.namespaces.read().unwrap();
+        namespaces
+            .get(namespace)
+            .is_none_or(|(_, read_at)| read_at.elapsed() > NAMESPACE_CACHE_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sources() {
+        let sources = parse_sources("spec, label=demeter.run/project,prefix=prj-").unwrap();
+        assert_eq!(
+            sources,
+            vec![
+                ProjectSource::Spec,
+                ProjectSource::Label("demeter.run/project".into()),
+                ProjectSource::Prefix("prj-".into()),
+            ]
+        );
+        assert!(parse_sources("spec,label=").is_err());
+        assert!(parse_sources("namespace").is_err());
+    }
+
+    #[test]
+    fn test_report_unresolved() {
+        let projects = Projects::new(parse_sources("label=demeter.run/project").unwrap());
+        assert!(projects.report_unresolved("default"));
+        assert!(!projects.report_unresolved("default"));
+        assert!(projects.report_unresolved("other"));
+
+        // Read again, the namespace is reported again
+        let metadata = (ObjectMeta::default(), Instant::now());
+        projects
+            .namespaces
+            .write()
+            .unwrap()
+            .insert("default".into(), metadata);
+        assert!(projects.report_unresolved("default"));
+        assert!(!projects.report_unresolved("default"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let sources = parse_sources("spec,annotation=demeter.run/project,prefix=prj-").unwrap();
+        let metadata = ObjectMeta {
+            annotations: Some(BTreeMap::from([(
+                "demeter.run/project".to_string(),
+                "annotated".to_string(),
+            )])),
+            ..Default::default()
+        };
+
+        // The projectRef can't override the project of the namespace
+        let project = resolve(&sources, "prj-test", Some("explicit"), Some(&metadata));
+        assert_eq!(project.as_deref(), Some("annotated"));
+
+        let project = resolve(&sources, "default", Some("explicit"), None);
+        assert_eq!(project.as_deref(), Some("explicit"));
+
+        let sources = parse_sources("prefix=prj-").unwrap();
+        assert!(resolve(&sources, "default", Some("explicit"), None).is_none());
+
+        let sources = parse_sources("spec,annotation=demeter.run/project,prefix=prj-").unwrap();
+
+        let project = resolve(&sources, "prj-test", None, Some(&metadata));
+        assert_eq!(project.as_deref(), Some("annotated"));
+
+        let project = resolve(&sources, "prj-test", None, None);
+        assert_eq!(project.as_deref(), Some("test"));
+
+        // Namespaces without the prefix used to panic
+        assert!(resolve(&sources, "default", None, None).is_none());
+        assert!(resolve(&sources, "prj-", None, None).is_none());
+        assert!(resolve(&sources, "team-prj-test", None, None).is_none());
+    }
+}
@@ -0,0 +1,365 @@
+use kube::{api::ListParams, runtime::reflector::Store, Api, ResourceExt};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    controller::{DbSyncPort, QUOTA_CONDITION},
+    get_config, Error, State,
+};
+
+// Reason of the quota condition of a port that was never provisioned because of the quota
+pub const NOT_PROVISIONED_REASON: &str = "NotProvisioned";
+// Ports waiting for quota don't get an event when another port is deleted
+pub const QUOTA_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Limits of the ports of a project on each network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub ports: u32,
+    /// Sum of the throughput tiers of the ports
+    pub tier_units: u32,
+}
+
+impl FromStr for Quota {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::ConfigError(format!("invalid quota {s}, expected ports:tierUnits"));
+        let (ports, tier_units) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Quota {
+            ports: ports.parse().map_err(|_| invalid())?,
+            tier_units: tier_units.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+pub fn parse_quotas(quotas: &str) -> Result<HashMap<String, Quota>, Error> {
+    quotas
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, quota) = entry.split_once('=').ok_or(Error::ConfigError(format!(
+                "invalid quota entry {entry}, expected name=ports:tierUnits"
+            )))?;
+            Ok((name.trim().to_string(), quota.trim().parse()?))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// No quota applies to the port
+    Unlimited,
+    Within(Quota),
+    /// The port is over the quota, the message says which limit
+    Exceeded(String),
+    /// The tier or network change of a provisioned port doesn't fit the quota, it keeps the ones
+    /// it has
+    HeldBack(String),
+}
+
+// The ports of the controller's reflector, so a quota check doesn't list every port. It's only
+// there while this replica leads.
+#[derive(Default)]
+pub struct Ports {
+    store: RwLock<Option<Store<DbSyncPort>>>,
+}
+
+impl Ports {
+    pub fn set(&self, store: Option<Store<DbSyncPort>>) {
+        *self.store.write().unwrap() = store;
+    }
+
+    pub async fn list(&self, state: &State) -> Result<Vec<Arc<DbSyncPort>>, Error> {
+        if let Some(store) = self.store.read().unwrap().as_ref() {
+            return Ok(store.state());
+        }
+
+        let api: Api<DbSyncPort> = Api::all(state.kube_client.clone());
+        let ports = api.list(&ListParams::default()).await?.items;
+        Ok(ports.into_iter().map(Arc::new).collect())
+    }
+}
+
+fn tier_units(port: &DbSyncPort) -> u32 {
+    port.spec.throughput_tier.unwrap_or_default()
+}
+
+// The tier the port is metered at, it stays behind the spec while a higher one doesn't fit
+pub fn applied_tier(port: &DbSyncPort) -> u32 {
+    port.status
+        .as_ref()
+        .and_then(|status| status.throughput_tier)
+        .unwrap_or(tier_units(port))
+}
+
+// The network the user of the port is on, the one of the spec until it's provisioned
+fn applied_network(state: &State, port: &DbSyncPort) -> String {
+    port.status
+        .as_ref()
+        .and_then(|status| status.network.clone())
+        .unwrap_or_else(|| port.network(state))
+}
+
+// Ports that were never provisioned because of the quota wait for it, the others keep working
+// when they are over it.
+pub fn is_pending(port: &DbSyncPort) -> bool {
+    match &port.status {
+        None => true,
+        Some(status) => status.conditions.iter().any(|condition| {
+            condition.type_ == QUOTA_CONDITION
+                && condition.status == "True"
+                && condition.reason == NOT_PROVISIONED_REASON
+        }),
+    }
+}
+
+// Provisioned ports use the quota first, then the pending ones in the order they were created,
+// so every reconcile comes to the same result whatever port it's for. A provisioned port that
+// changes its tier or network must fit next to all the other provisioned ports.
+fn admit(quota: Quota, port: &DbSyncPort, scope: &[&DbSyncPort], change: bool) -> Admission {
+    let is_port = |other: &DbSyncPort| {
+        other.namespace() == port.namespace() && other.name_any() == port.name_any()
+    };
+
+    if change {
+        let others: Vec<&&DbSyncPort> = scope
+            .iter()
+            .filter(|other| !is_port(other) && !is_pending(other))
+            .collect();
+        let ports = others.len() as u32 + 1;
+        let units = others.iter().map(|other| applied_tier(other)).sum::<u32>() + tier_units(port);
+        if ports <= quota.ports && units <= quota.tier_units {
+            return Admission::Within(quota);
+        }
+        return Admission::HeldBack(format!(
+            "the change needs {ports} ports and {units} tier units of {} and {} on {}",
+            quota.ports, quota.tier_units, port.spec.network
+        ));
+    }
+
+    let mut ordered: Vec<&DbSyncPort> = scope.to_vec();
+    ordered.sort_by_key(|other| {
+        (
+            is_pending(other),
+            other.creation_timestamp(),
+            other.namespace(),
+            other.name_any(),
+        )
+    });
+
+    let (mut ports, mut units) = (0, 0);
+    for other in ordered {
+        let fits = ports < quota.ports && units + applied_tier(other) <= quota.tier_units;
+        if fits || !is_pending(other) {
+            ports += 1;
+            units += applied_tier(other);
+        }
+
+        if !is_port(other) {
+            continue;
+        }
+        if fits {
+            return Admission::Within(quota);
+        }
+        return Admission::Exceeded(format!(
+            "{ports} ports and {units} tier units used of {} and {} on {}",
+            quota.ports, quota.tier_units, port.spec.network
+        ));
+    }
+
+    Admission::Within(quota)
+}
+
+// The quota is the one named by the namespace label, or the one of the project, or `default`
+async fn quota_of(state: &State, port: &DbSyncPort, project: &str) -> Option<Quota> {
+    let config = get_config();
+    let ns = port.namespace().unwrap();
+    let metadata = state.projects.namespace(&state.kube_client, &ns).await;
+    let label = metadata
+        .and_then(|metadata| metadata.labels)
+        .and_then(|labels| labels.get(&config.quota_label).cloned());
+
+    let quota = [label.as_deref(), Some(project), Some("default")]
+        .into_iter()
+        .flatten()
+        .find_map(|name| config.quotas.get(name).copied());
+    quota
+}
+
+async fn scope_of(
+    state: &State,
+    port: &DbSyncPort,
+    scopes: &mut HashMap<String, String>,
+) -> String {
+    let ns = port.namespace().unwrap();
+    if let Some(scope) = scopes.get(&ns) {
+        return scope.clone();
+    }
+    let project = state.projects.resolve(&state.kube_client, &ns, None).await;
+    let scope = project.unwrap_or(ns.clone());
+    scopes.insert(ns, scope.clone());
+    scope
+}
+
+// The ports of a project on the same network share its quota. The project is the one of the
+// namespace, since the projectRef of a port could name any project. Ports of namespaces without a
+// project are limited by namespace.
+pub async fn check(state: &State, port: &DbSyncPort) -> Result<Admission, Error> {
+    if get_config().quotas.is_empty() {
+        return Ok(Admission::Unlimited);
+    }
+
+    // Each namespace is resolved once, most ports of a project share a few namespaces
+    let mut scopes: HashMap<String, String> = HashMap::new();
+    let scope = scope_of(state, port, &mut scopes).await;
+
+    let Some(quota) = quota_of(state, port, &scope).await else {
+        return Ok(Admission::Unlimited);
+    };
+
+    // The other ports count where they are provisioned, this one where the spec wants it
+    let network = port.network(state);
+    let ports = state.ports.list(state).await?;
+    let mut same_scope = Vec::new();
+    for other in ports.iter() {
+        if applied_network(state, other) != network || other.metadata.deletion_timestamp.is_some() {
+            continue;
+        }
+        if scope_of(state, other, &mut scopes).await == scope {
+            same_scope.push(other.as_ref());
+        }
+    }
+
+    let change = !is_pending(port)
+        && (applied_tier(port) != tier_units(port) || applied_network(state, port) != network);
+    Ok(admit(quota, port, &same_scope, change))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+    fn port(name: &str, tier: u32, created: i64, provisioned: bool) -> DbSyncPort {
+        let mut port = fixtures::port(name, "cardano-mainnet");
+        port.spec.throughput_tier = Some(tier);
+        let created = chrono::DateTime::from_timestamp(created, 0).unwrap();
+        port.metadata.creation_timestamp = Some(Time(created));
+        match provisioned {
+            true => fixtures::provisioned(port, &[name]),
+            false => port,
+        }
+    }
+
+    #[test]
+    fn test_parse_quotas() {
+        let quotas = parse_quotas("default=2:3, pro=10:30").unwrap();
+        assert_eq!(
+            quotas.get("default"),
+            Some(&Quota {
+                ports: 2,
+                tier_units: 3
+            })
+        );
+        assert_eq!(quotas.len(), 2);
+        assert!(parse_quotas("").unwrap().is_empty());
+        assert!(parse_quotas("default=2").is_err());
+        assert!(parse_quotas("default").is_err());
+    }
+
+    #[test]
+    fn test_admit() {
+        let quota = Quota {
+            ports: 2,
+            tier_units: 3,
+        };
+        let first = port("first", 1, 1, true);
+        let second = port("second", 1, 2, false);
+        let third = port("third", 0, 3, false);
+        let scope = [&third, &second, &first];
+
+        assert_eq!(
+            admit(quota, &first, &scope, false),
+            Admission::Within(quota)
+        );
+        assert_eq!(
+            admit(quota, &second, &scope, false),
+            Admission::Within(quota)
+        );
+        assert!(matches!(
+            admit(quota, &third, &scope, false),
+            Admission::Exceeded(_)
+        ));
+
+        // A pending port that doesn't fit the tier units leaves room for the next ones
+        let large = port("large", 3, 2, false);
+        let scope = [&first, &large, &third];
+        assert!(matches!(
+            admit(quota, &large, &scope, false),
+            Admission::Exceeded(_)
+        ));
+        assert_eq!(
+            admit(quota, &third, &scope, false),
+            Admission::Within(quota)
+        );
+
+        // Provisioned ports are never blocked, they only report the quota
+        let provisioned = port("provisioned", 3, 4, true);
+        let scope = [&first, &provisioned];
+        assert!(matches!(
+            admit(quota, &provisioned, &scope, false),
+            Admission::Exceeded(_)
+        ));
+    }
+
+    #[test]
+    fn test_admit_change() {
+        let quota = Quota {
+            ports: 2,
+            tier_units: 3,
+        };
+        let applied = |mut port: DbSyncPort, tier: u32| {
+            port.status.as_mut().unwrap().throughput_tier = Some(tier);
+            port
+        };
+        let first = applied(port("first", 1, 1, true), 1);
+        let second = applied(port("second", 1, 2, true), 1);
+        assert_eq!(applied_tier(&first), 1);
+
+        // A higher tier is applied while it fits next to the other provisioned ports
+        let upgraded = applied(port("second", 2, 2, true), 1);
+        let scope = [&first, &upgraded];
+        assert_eq!(
+            admit(quota, &upgraded, &scope, true),
+            Admission::Within(quota)
+        );
+
+        let upgraded = applied(port("second", 3, 2, true), 1);
+        let scope = [&first, &upgraded];
+        assert!(matches!(
+            admit(quota, &upgraded, &scope, true),
+            Admission::HeldBack(_)
+        ));
+        assert_eq!(applied_tier(&upgraded), 1);
+
+        // A port moving to a network takes a port of the quota there, pending ports don't count
+        let moving = applied(port("moving", 1, 3, true), 1);
+        let pending = port("pending", 1, 0, false);
+        let scope = [&first, &pending];
+        assert_eq!(
+            admit(quota, &moving, &scope, true),
+            Admission::Within(quota)
+        );
+        let scope = [&first, &second, &pending];
+        assert!(matches!(
+            admit(quota, &moving, &scope, true),
+            Admission::HeldBack(_)
+        ));
+    }
+}
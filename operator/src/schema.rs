@@ -0,0 +1,313 @@
+use kube::{api::ListParams, Api, ResourceExt};
+use lazy_static::lazy_static;
+use std::{collections::HashMap, sync::Arc};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    controller::{set_condition, update_status, DbSyncPortCondition},
+    get_config,
+    postgres::{Postgres, SchemaObjects},
+    DbSyncPort, Error, State,
+};
+
+pub const DEGRADED_CONDITION: &str = "Degraded";
+
+lazy_static! {
+    static ref INDEXES: Vec<Index> = parse_indexes(include_str!("../sql/indexes.sql"));
+}
+
+// An index of the dbsync schema, built concurrently
+#[derive(Debug, PartialEq)]
+struct Index {
+    name: String,
+    table: String,
+    statement: String,
+}
+
+fn parse_indexes(sql: &str) -> Vec<Index> {
+    sql.lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("CREATE"))
+        .map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let token_after = |keyword: &str| {
+                tokens
+                    .iter()
+                    .position(|token| *token == keyword)
+                    .and_then(|i| tokens.get(i + 1))
+            };
+            let name = token_after("EXISTS").expect("index statements must use IF NOT EXISTS");
+            let table = token_after("ON").expect("index statements must have a table");
+            let table = table.trim_start_matches("public.");
+            let table = table.split('(').next().unwrap_or(table);
+
+            Index {
+                name: name.to_string(),
+                table: table.to_string(),
+                statement: line.replacen(" INDEX ", " INDEX CONCURRENTLY ", 1),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Default)]
+struct SchemaStatus {
+    created: bool,
+    objects: SchemaObjects,
+}
+
+impl SchemaStatus {
+    // The tables of an older or newer dbsync version aren't there, their indexes aren't missing
+    fn missing_indexes(&self) -> Vec<&'static Index> {
+        INDEXES
+            .iter()
+            .filter(|index| self.objects.tables.contains(&index.table))
+            .filter(|index| !self.objects.valid_indexes.contains(&index.name))
+            .collect()
+    }
+
+    fn missing(&self) -> Vec<(&'static str, i64)> {
+        vec![
+            ("dbsync_schema", (!self.created).into()),
+            (
+                "pg_stat_statements",
+                (!self.objects.pg_stat_statements).into(),
+            ),
+            ("pgbouncer", (!self.objects.pgbouncer).into()),
+            ("user_search", (!self.objects.user_search).into()),
+            ("indexes", self.missing_indexes().len() as i64),
+        ]
+    }
+
+    fn is_ready(&self) -> bool {
+        self.missing().iter().all(|(_, count)| *count == 0)
+    }
+}
+
+async fn schema_status(pg: &Postgres) -> Result<SchemaStatus, Error> {
+    if !pg.schema_created().await? {
+        return Ok(SchemaStatus::default());
+    }
+
+    Ok(SchemaStatus {
+        created: true,
+        objects: pg.schema_objects().await?,
+    })
+}
+
+async fn apply(state: &State, pg: &Postgres, status: &SchemaStatus) -> Result<(), Error> {
+    let config = get_config();
+
+    if !status.objects.pg_stat_statements {
+        pg.create_pg_stat_statements().await?;
+    }
+    if !status.objects.pgbouncer {
+        match &config.pgbouncer_password {
+            Some(password) => pg.create_pgbouncer_role(password).await?,
+            None => warn!(
+                instance = pg.instance,
+                "pgbouncer role is missing and PGBOUNCER_PASSWORD is not set"
+            ),
+        }
+    }
+    if !status.objects.user_search {
+        pg.create_user_search().await?;
+    }
+
+    let mut created = 0;
+    for index in status.missing_indexes() {
+        if state.shutdown.is_triggered() || !state.leadership.is_leader() {
+            break;
+        }
+        // A failed build must not block the others
+        let result = async {
+            if status.objects.invalid_indexes.contains(&index.name) {
+                pg.drop_index_concurrently(&index.name).await?;
+            }
+            pg.create_index_concurrently(&index.name, &index.statement)
+                .await
+        };
+        match result.await {
+            Ok(()) => created += 1,
+            Err(err) => warn!(
+                error = err.to_string(),
+                instance = pg.instance,
+                index = index.name,
+                "fail to create index"
+            ),
+        }
+    }
+    if created > 0 {
+        info!(instance = pg.instance, count = created, "indexes created");
+        pg.analyze().await?;
+    }
+
+    Ok(())
+}
+
+#[instrument("prepare instance", skip_all, fields(network = %network, instance = %pg.instance))]
+async fn prepare(state: &State, network: &str, pg: &Postgres) -> Result<bool, Error> {
+    let mut status = schema_status(pg).await?;
+
+    if status.created && !status.is_ready() && get_config().schema_apply {
+        info!(missing = ?status.missing(), "preparing instance");
+        let result = apply(state, pg, &status).await;
+        status = schema_status(pg).await?;
+        result?;
+    }
+
+    state
+        .metrics
+        .schema_status(network, &pg.instance, &status.missing());
+
+    Ok(status.is_ready())
+}
+
+// Ports are degraded while an instance of their network is missing any prerequisite
+async fn update_ports(
+    state: &State,
+    unprepared: &HashMap<String, Vec<String>>,
+) -> Result<(), Error> {
+    let api = Api::<DbSyncPort>::all(state.kube_client.clone());
+
+    for crd in api.list(&ListParams::default()).await?.items {
+        let Some(status) = &crd.status else {
+            continue;
+        };
+
+        let instances = unprepared
+            .get(&crd.network(state))
+            .cloned()
+            .unwrap_or_default();
+        let set_degraded = |conditions: &mut Vec<DbSyncPortCondition>| match instances.is_empty() {
+            false => set_condition(
+                conditions,
+                DEGRADED_CONDITION,
+                true,
+                "InstanceNotPrepared",
+                format!("instances not prepared: {}", instances.join(", ")),
+            ),
+            true => set_condition(
+                conditions,
+                DEGRADED_CONDITION,
+                false,
+                "InstancesPrepared",
+                String::new(),
+            ),
+        };
+        if !set_degraded(&mut status.conditions.clone()) {
+            continue;
+        }
+
+        // The listed port may be behind, only the Degraded condition is written on the current one
+        let ns = crd.namespace().unwrap();
+        let api = Api::<DbSyncPort>::namespaced(state.kube_client.clone(), &ns);
+        update_status(&api, &crd.name_any(), |current| {
+            set_degraded(&mut current.conditions)
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+// Replaces the index job and the queries that were applied by hand after dbsync first ran. Only
+// the leader applies them, one instance at a time, since the index builds are expensive. It's
+// supervised like the other loops, so it keeps running until the shutdown even when disabled.
+#[instrument("schema bootstrap run", skip_all)]
+pub async fn run_schema_bootstrap(state: Arc<State>) {
+    let config = get_config();
+    if config.schema_check_interval.is_zero() {
+        state.shutdown.wait().await;
+        return;
+    }
+
+    loop {
+        if state.leadership.is_leader() && !state.shutdown.is_triggered() {
+            let mut unprepared: HashMap<String, Vec<String>> = HashMap::new();
+
+            for (network, connections) in state.pg_connections().iter() {
+                for pg in connections {
+                    let ready = match prepare(&state, network, pg).await {
+                        Ok(ready) => ready,
+                        Err(err) => {
+                            error!(
+                                error = err.to_string(),
+                                instance = pg.instance,
+                                "fail to prepare instance"
+                            );
+                            false
+                        }
+                    };
+                    if !ready {
+                        unprepared
+                            .entry(network.clone())
+                            .or_default()
+                            .push(pg.instance.clone());
+                    }
+                }
+            }
+
+            if let Err(err) = update_ports(&state, &unprepared).await {
+                error!(error = err.to_string(), "fail to update degraded ports");
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(config.schema_check_interval) => {},
+            _ = state.shutdown.wait() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_indexes() {
+        let sql = "
+            -- dbsync indexes
+            CREATE UNIQUE INDEX IF NOT EXISTS block_pkey ON public.block USING btree (id);
+            CREATE INDEX IF NOT EXISTS stake_address_idx ON stake_address(\"view\");
+        ";
+
+        assert_eq!(
+            parse_indexes(sql),
+            vec![
+                Index {
+                    name: "block_pkey".to_string(),
+                    table: "block".to_string(),
+                    statement: "CREATE UNIQUE INDEX CONCURRENTLY IF NOT EXISTS block_pkey ON public.block USING btree (id);".to_string()
+                },
+                Index {
+                    name: "stake_address_idx".to_string(),
+                    table: "stake_address".to_string(),
+                    statement: "CREATE INDEX CONCURRENTLY IF NOT EXISTS stake_address_idx ON stake_address(\"view\");".to_string()
+                },
+            ]
+        );
+        assert_eq!(INDEXES.len(), 192);
+    }
+
+    #[test]
+    fn test_missing_indexes() {
+        let mut status = SchemaStatus {
+            created: true,
+            ..Default::default()
+        };
+        status.objects.tables = HashSet::from(["block".to_string()]);
+        status.objects.valid_indexes = HashSet::from(["block_pkey".to_string()]);
+
+        let missing = status.missing_indexes();
+        assert!(!missing.is_empty());
+        assert!(missing.iter().all(|index| index.table == "block"));
+        assert!(missing.iter().all(|index| index.name != "block_pkey"));
+
+        // Without the tables of the indexes there is nothing to build
+        status.objects.tables.clear();
+        assert!(status.missing_indexes().is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+use std::future::Future;
+use tokio::sync::watch;
+
+// Shared by every task so a single signal stops new work everywhere. The controller drains the
+// in-flight reconciles, the collector records a last usage window, /metrics is kept up until it's
+// scraped and then the pools are closed.
+pub struct Shutdown {
+    tx: watch::Sender<bool>,
+    scrapes: watch::Sender<u64>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(false);
+        let (scrapes, _) = watch::channel(0);
+        Self { tx, scrapes }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    pub fn wait(&self) -> impl Future<Output = ()> + Send + Sync + 'static {
+        let mut rx = self.tx.subscribe();
+        async move {
+            let _ = rx.wait_for(|triggered| *triggered).await;
+        }
+    }
+
+    pub fn scraped(&self) {
+        self.scrapes.send_modify(|scrapes| *scrapes += 1);
+    }
+
+    // Resolves on the first scrape after the call, earlier scrapes don't hold the last window
+    pub fn next_scrape(&self) -> impl Future<Output = ()> + Send + Sync + 'static {
+        let mut rx = self.scrapes.subscribe();
+        async move {
+            let _ = rx.changed().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_next_scrape() {
+        let shutdown = Shutdown::default();
+        shutdown.scraped();
+
+        let next_scrape = shutdown.next_scrape();
+        let pending = tokio::time::timeout(Duration::from_millis(10), next_scrape).await;
+        assert!(pending.is_err());
+
+        let next_scrape = shutdown.next_scrape();
+        shutdown.scraped();
+        let scraped = tokio::time::timeout(Duration::from_millis(10), next_scrape).await;
+        assert!(scraped.is_ok());
+    }
+}
@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+use futures::future;
+use kube::{
+    api::{Patch, PatchParams},
+    Api, ResourceExt,
+};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use tracing::{error, warn};
+
+use crate::{get_config, postgres::RoleStats, DbSyncPort, DbSyncPortStats, State};
+
+// Samples the live usage of each port on the postgres instances and writes a summary into its
+// status. Ports are only sampled once every PORT_STATS_INTERVAL to avoid churn on the api server.
+pub async fn collect_port_stats(
+    state: &State,
+    crds: &[DbSyncPort],
+    exec_times: &mut HashMap<(String, String), f64>,
+) {
+    let now = Utc::now();
+    let interval = chrono::Duration::from_std(get_config().port_stats_interval).unwrap();
+
+    prune_exec_times(state, crds, exec_times);
+
+    let mut due: HashMap<String, Vec<&DbSyncPort>> = HashMap::new();
+    for crd in crds {
+        let Some(status) = &crd.status else {
+            continue;
+        };
+
+        let sampled_at = status
+            .stats
+            .as_ref()
+            .and_then(|stats| DateTime::parse_from_rfc3339(&stats.sampled_at).ok());
+        if sampled_at.is_some_and(|sampled_at| now - sampled_at.with_timezone(&Utc) < interval) {
+            continue;
+        }
+
+        due.entry(crd.network(state)).or_default().push(crd);
+    }
+
+    for (network, crds) in due {
+        let Ok(connections) = state.get_pg_by_network(&network) else {
+            continue;
+        };
+
+        // The credentials of a port are summed up in the stats of the port
+        let mut owners: HashMap<String, String> = HashMap::new();
+        for crd in crds.iter() {
+            let status = crd.status.as_ref().unwrap();
+            for username in status.usernames() {
+                owners.insert(username.to_string(), status.username.clone());
+            }
+        }
+        let usernames: Vec<String> = owners.keys().cloned().collect();
+
+        let results =
+            future::join_all(connections.iter().map(|pg| pg.role_stats(&usernames))).await;
+
+        let mut summaries: HashMap<&str, DbSyncPortStats> = HashMap::new();
+        for (pg, result) in connections.iter().zip(results) {
+            let stats = match result {
+                Ok(stats) => stats,
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        network, "error to sample role stats"
+                    );
+                    state.metrics.metrics_failure(&err);
+                    continue;
+                }
+            };
+
+            for username in usernames.iter() {
+                let role = stats.get(username).cloned().unwrap_or_default();
+                let summary = summaries.entry(&owners[username]).or_default();
+                summary.connections += role.connections;
+                summary.active_queries += role.active_queries;
+                summary.longest_query_seconds = summary
+                    .longest_query_seconds
+                    .max(role.longest_query_seconds);
+                summary.recent_query_seconds +=
+                    recent_exec_time(exec_times, &pg.instance, username, &role);
+            }
+        }
+
+        for crd in crds {
+            let username = crd.status.as_ref().unwrap().username.as_str();
+            let Some(mut summary) = summaries.remove(username) else {
+                continue;
+            };
+            summary.sampled_at = now.to_rfc3339();
+
+            let namespace = crd.namespace().unwrap();
+            let api: Api<DbSyncPort> = Api::namespaced(state.kube_client.clone(), &namespace);
+            let patch = Patch::Merge(json!({ "status": { "stats": summary } }));
+            if let Err(err) = api
+                .patch_status(&crd.name_any(), &PatchParams::default(), &patch)
+                .await
+            {
+                warn!(error = err.to_string(), "error to patch port stats");
+            }
+        }
+    }
+}
+
+// Keeps the baselines of the roles of the current ports on the current instances, so deleted
+// ports and removed instances don't stay in the map
+fn prune_exec_times(
+    state: &State,
+    crds: &[DbSyncPort],
+    exec_times: &mut HashMap<(String, String), f64>,
+) {
+    let usernames: HashSet<&str> = crds
+        .iter()
+        .filter_map(|crd| crd.status.as_ref())
+        .flat_map(|status| status.usernames())
+        .collect();
+    let instances: HashSet<String> = state
+        .pg_connections()
+        .values()
+        .flatten()
+        .map(|pg| pg.instance.clone())
+        .collect();
+
+    retain_exec_times(exec_times, &usernames, &instances);
+}
+
+fn retain_exec_times(
+    exec_times: &mut HashMap<(String, String), f64>,
+    usernames: &HashSet<&str>,
+    instances: &HashSet<String>,
+) {
+    exec_times.retain(|(instance, username), _| {
+        instances.contains(instance) && usernames.contains(username.as_str())
+    });
+}
+
+fn recent_exec_time(
+    exec_times: &mut HashMap<(String, String), f64>,
+    instance: &str,
+    username: &str,
+    role: &RoleStats,
+) -> f64 {
+    let Some(total) = role.total_exec_time_ms else {
+        return 0.;
+    };
+
+    let key = (instance.to_string(), username.to_string());
+    let previous = exec_times.insert(key, total);
+
+    // pg_stat_statements is cumulative, so the first sample and resets only record a baseline
+    match previous {
+        Some(previous) if total >= previous => (total - previous) / 1000.,
+        _ => 0.,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_exec_time() {
+        let mut exec_times = HashMap::new();
+        let role = |total| RoleStats {
+            total_exec_time_ms: Some(total),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            recent_exec_time(&mut exec_times, "a:5432", "user", &role(1000.)),
+            0.
+        );
+        assert_eq!(
+            recent_exec_time(&mut exec_times, "a:5432", "user", &role(3000.)),
+            2.
+        );
+        // A reset of pg_stat_statements only records a new baseline
+        assert_eq!(
+            recent_exec_time(&mut exec_times, "a:5432", "user", &role(500.)),
+            0.
+        );
+
+        recent_exec_time(&mut exec_times, "b:5432", "user", &role(500.));
+        recent_exec_time(&mut exec_times, "a:5432", "deleted", &role(500.));
+        let usernames = HashSet::from(["user"]);
+        let instances = HashSet::from(["a:5432".to_string()]);
+        retain_exec_times(&mut exec_times, &usernames, &instances);
+        assert_eq!(
+            exec_times.keys().collect::<Vec<_>>(),
+            vec![&("a:5432".to_string(), "user".to_string())]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use chrono::Utc;
+use futures::future;
+use kube::{Api, ResourceExt};
+use std::collections::HashMap;
+use tracing::{error, warn};
+
+use crate::{
+    controller::{set_condition, update_status, DbSyncPortCondition, DbSyncPortStatus},
+    get_config, DbSyncPort, State,
+};
+
+pub const CHAIN_SYNCED_CONDITION: &str = "ChainSynced";
+
+// The lag is only written again when it moved this much, the condition flips right away
+const LAG_PATCH_STEP: i64 = 60;
+
+// The lag of the slowest instance, unknown when the tip of any instance couldn't be read
+fn network_lag(lags: Vec<Option<i64>>) -> Option<i64> {
+    lags.into_iter().try_fold(0, |max, lag| Some(max.max(lag?)))
+}
+
+fn set_sync_condition(
+    conditions: &mut Vec<DbSyncPortCondition>,
+    lag: Option<i64>,
+    threshold: i64,
+) -> bool {
+    match lag {
+        Some(lag) if lag <= threshold => set_condition(
+            conditions,
+            CHAIN_SYNCED_CONDITION,
+            true,
+            "Synced",
+            format!("dbsync is less than {threshold}s behind the chain tip"),
+        ),
+        Some(_) => set_condition(
+            conditions,
+            CHAIN_SYNCED_CONDITION,
+            false,
+            "Lagging",
+            format!("dbsync is more than {threshold}s behind the chain tip"),
+        ),
+        None => set_condition(
+            conditions,
+            CHAIN_SYNCED_CONDITION,
+            false,
+            "TipUnknown",
+            "the tip of dbsync couldn't be read".into(),
+        ),
+    }
+}
+
+fn lag_moved(previous: Option<i64>, lag: Option<i64>) -> bool {
+    match (previous, lag) {
+        (Some(previous), Some(lag)) => (previous - lag).abs() >= LAG_PATCH_STEP,
+        (previous, lag) => previous != lag,
+    }
+}
+
+// Reads the tip of each instance and compares the time of its latest block with the wall clock.
+// A network is as synced as its slowest available instance, since a client may land on any of
+// them.
+pub async fn collect_sync_status(state: &State, crds: &[DbSyncPort]) {
+    let now = Utc::now().timestamp() as f64;
+
+    let mut lags: HashMap<String, Option<i64>> = HashMap::new();
+    for (network, connections) in state.pg_connections().iter() {
+        // Excluded instances don't serve clients, they don't hold the network back
+        let (connections, _) = state.instances.available(network, connections);
+        let results = future::join_all(connections.iter().map(|pg| pg.tip())).await;
+
+        let mut instance_lags = Vec::new();
+        for (pg, result) in connections.iter().zip(results) {
+            let lag = match result {
+                Ok(Some((slot, time))) => {
+                    let lag = (now - time).max(0.);
+                    state.metrics.sync_status(network, &pg.instance, slot, lag);
+                    Some(lag as i64)
+                }
+                Ok(None) => None,
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        network,
+                        instance = pg.instance,
+                        "error to read dbsync tip"
+                    );
+                    state.metrics.metrics_failure(&err);
+                    None
+                }
+            };
+            instance_lags.push(lag);
+        }
+        lags.insert(network.clone(), network_lag(instance_lags));
+    }
+
+    let threshold = get_config().sync_lag_threshold.as_secs() as i64;
+    for crd in crds {
+        let Some(status) = &crd.status else {
+            continue;
+        };
+        let Some(lag) = lags.get(&crd.network(state)).copied() else {
+            continue;
+        };
+
+        // The listed port may be behind, only the ChainSynced condition and the lag are written
+        // on the current one
+        let update = |status: &mut DbSyncPortStatus| {
+            let changed = set_sync_condition(&mut status.conditions, lag, threshold);
+            let moved = lag_moved(status.sync_lag_seconds, lag);
+            if moved {
+                status.sync_lag_seconds = lag;
+            }
+            changed || moved
+        };
+        if !update(&mut status.clone()) {
+            continue;
+        }
+
+        let api: Api<DbSyncPort> =
+            Api::namespaced(state.kube_client.clone(), &crd.namespace().unwrap());
+        if let Err(err) = update_status(&api, &crd.name_any(), update).await {
+            warn!(error = err.to_string(), "error to patch port sync status");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_lag() {
+        assert_eq!(network_lag(vec![Some(5), Some(120), Some(30)]), Some(120));
+        assert_eq!(network_lag(vec![Some(5), None]), None);
+        assert_eq!(network_lag(vec![]), Some(0));
+    }
+
+    #[test]
+    fn test_sync_condition() {
+        let mut conditions = Vec::new();
+        assert!(set_sync_condition(&mut conditions, Some(300), 300));
+        assert_eq!(conditions[0].status, "True");
+        assert_eq!(conditions[0].reason, "Synced");
+
+        // The lag alone doesn't change the condition
+        assert!(!set_sync_condition(&mut conditions, Some(10), 300));
+
+        assert!(set_sync_condition(&mut conditions, Some(301), 300));
+        assert_eq!(conditions[0].status, "False");
+        assert_eq!(conditions[0].reason, "Lagging");
+
+        assert!(set_sync_condition(&mut conditions, None, 300));
+        assert_eq!(conditions[0].status, "False");
+        assert_eq!(conditions[0].reason, "TipUnknown");
+        assert_eq!(conditions.len(), 1);
+    }
+
+    #[test]
+    fn test_lag_moved() {
+        assert!(!lag_moved(Some(100), Some(159)));
+        assert!(lag_moved(Some(100), Some(160)));
+        assert!(lag_moved(Some(100), Some(40)));
+        assert!(lag_moved(None, Some(0)));
+        assert!(lag_moved(Some(0), None));
+        assert!(!lag_moved(None, None));
+    }
+}
@@ -0,0 +1,178 @@
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    runtime,
+    trace::{Config as TraceConfig, TracerProvider},
+    Resource,
+};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::{get_config, Error, LogFormat};
+
+const SERVICE_NAME: &str = "ext-cardano-dbsync";
+
+// Installs the global subscriber. The returned provider must be shut down before exiting so the
+// pending spans are exported.
+pub fn init() -> Result<Option<TracerProvider>, Error> {
+    let config = get_config();
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let fmt_layer = match config.log_format {
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+        LogFormat::Text => fmt::layer().boxed(),
+    };
+
+    let provider = config
+        .otlp_endpoint
+        .as_ref()
+        .map(|endpoint| otlp_provider(&format!("{endpoint}/v1/traces")))
+        .transpose()?;
+
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(otel_layer)
+        .with(filter)
+        .init();
+
+    Ok(provider)
+}
+
+fn otlp_provider(endpoint: &str) -> Result<TracerProvider, Error> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(endpoint);
+
+    let trace_config = TraceConfig::default().with_resource(Resource::new(vec![KeyValue::new(
+        "service.name",
+        SERVICE_NAME,
+    )]));
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace_config)
+        .install_batch(runtime::Tokio)
+        .map_err(|err| Error::ConfigError(format!("otlp exporter: {err}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{self, BoxFuture};
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    // Keeps the spans in memory instead of sending them to a collector
+    #[derive(Debug, Clone, Default)]
+    struct MemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for MemoryExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(future::ready(Ok(())))
+        }
+    }
+
+    // Answers one request of the exporter and sends back its request line and body
+    fn collector() -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            sender
+                .send((request_line.trim().to_string(), body))
+                .unwrap();
+        });
+        (endpoint, receiver)
+    }
+
+    // The batch exporter sends the spans from another worker of the runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_otlp_export() {
+        let (endpoint, requests) = collector();
+        let provider = otlp_provider(&format!("{endpoint}/v1/traces")).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("pg create user", instance = "localhost:5432").in_scope(|| {});
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap();
+
+        let (request_line, body) = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(request_line, "POST /v1/traces HTTP/1.1");
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"pg create user"));
+        assert!(contains(SERVICE_NAME.as_bytes()));
+    }
+
+    #[test]
+    fn test_span_tree() {
+        let exporter = MemoryExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("reconcile", namespace = "prj-test", name = "port").in_scope(
+                || {
+                    tracing::info_span!("pg create user", instance = "localhost:5432")
+                        .in_scope(|| {});
+                },
+            );
+        });
+
+        let spans = exporter.0.lock().unwrap();
+        let reconcile = spans.iter().find(|span| span.name == "reconcile").unwrap();
+        let create = spans
+            .iter()
+            .find(|span| span.name == "pg create user")
+            .unwrap();
+        assert_eq!(create.parent_span_id, reconcile.span_context.span_id());
+        assert!(reconcile
+            .attributes
+            .contains(&KeyValue::new("namespace", "prj-test")));
+    }
+}
@@ -0,0 +1,258 @@
+use bech32::ToBase32;
+use futures::Future;
+use kube::ResourceExt;
+use sha3::{Digest, Sha3_256};
+use std::sync::Arc;
+
+use crate::{get_config, postgres::Postgres, DbSyncPort, Error, State};
+
+// The data characters of bech32. Names are truncated, so they don't carry a checksum and aren't
+// valid bech32 strings, only the alphabet is kept.
+const CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+// Postgres truncates identifiers longer than 63 bytes
+pub const MAX_USERNAME_LENGTH: usize = 63;
+// Characters of the hash in the shortest name allowed, enough to make collisions unlikely
+const MIN_HASH_LENGTH: usize = 8;
+const MAX_ATTEMPTS: u32 = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsernameGenerator {
+    pub prefix: String,
+    pub length: usize,
+}
+
+impl UsernameGenerator {
+    pub fn new(prefix: &str, length: usize) -> Result<Self, Error> {
+        let valid_prefix = prefix.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+            && prefix
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_prefix {
+            return Err(Error::ConfigError(format!(
+                "username prefix {prefix} must be a lowercase postgres identifier"
+            )));
+        }
+
+        // The prefix, the `1` separator and the shortest hash must fit
+        let min_length = prefix.len() + 1 + MIN_HASH_LENGTH;
+        if !(min_length..=MAX_USERNAME_LENGTH).contains(&length) {
+            return Err(Error::ConfigError(format!(
+                "username length must be between {min_length} and {MAX_USERNAME_LENGTH}"
+            )));
+        }
+
+        Ok(Self {
+            prefix: prefix.into(),
+            length,
+        })
+    }
+
+    pub fn from_config() -> Self {
+        let config = get_config();
+        Self::new(&config.username_prefix, config.username_length).unwrap()
+    }
+
+    // The same seed always gives the same name, a new attempt is only made after a collision.
+    // The first attempt matches the names generated before the prefix and length were
+    // configurable.
+    pub fn candidate(&self, seed: &str, attempt: u32) -> String {
+        let mut hasher = Sha3_256::new();
+        match attempt {
+            0 => hasher.update(seed),
+            _ => hasher.update(format!("{seed}#{attempt}")),
+        }
+
+        let hash = hasher.finalize().to_base32();
+        let hash_length = (self.length - self.prefix.len() - 1).min(hash.len());
+        let hash: String = hash
+            .iter()
+            .take(hash_length)
+            .map(|c| CHARSET[c.to_u8() as usize] as char)
+            .collect();
+
+        format!("{}1{hash}", self.prefix)
+    }
+
+    // The first candidate for which `taken` is false
+    pub async fn generate<F, Fut>(&self, seed: &str, taken: F) -> Result<String, Error>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<bool, Error>>,
+    {
+        for attempt in 0..MAX_ATTEMPTS {
+            let candidate = self.candidate(seed, attempt);
+            if !taken(candidate.clone()).await? {
+                return Ok(candidate);
+            }
+        }
+
+        Err(Error::ConfigError(format!(
+            "no free username for {seed} after {MAX_ATTEMPTS} attempts"
+        )))
+    }
+
+    // Checks the candidates and their group against the other ports and the roles of every
+    // instance that isn't excluded. A role of the port itself, left on an instance that was
+    // excluded or created before its status was written, is in its group and is a match. Roles
+    // are shared by the databases of an instance, so each instance is checked once. The group is
+    // the one of the candidate for a primary credential.
+    pub async fn generate_role(
+        &self,
+        state: &State,
+        port: &DbSyncPort,
+        seed: &str,
+        group: Option<&str>,
+    ) -> Result<String, Error> {
+        let ports = state.ports.list(state).await?;
+        let mut instances: Vec<Postgres> = Vec::new();
+        for (network, connections) in state.pg_connections().iter() {
+            let (available, _) = state.instances.available(network, connections);
+            for pg in available {
+                if !instances.iter().any(|other| other.instance == pg.instance) {
+                    instances.push(pg);
+                }
+            }
+        }
+
+        self.generate(seed, |candidate| {
+            let (ports, instances) = (&ports, &instances);
+            let group = group.map_or_else(|| format!("{candidate}_group"), String::from);
+            async move {
+                if claimed_by_other(ports, port, &candidate)
+                    || claimed_by_other(ports, port, &group)
+                {
+                    return Ok(true);
+                }
+                for pg in instances {
+                    if pg.user_exist(&candidate).await? && !pg.is_member(&candidate, &group).await?
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+        })
+        .await
+    }
+
+    // The name the group of a port with a username of its own is derived from. Only the other
+    // ports are checked, the instances aren't looked up for a role the port doesn't create.
+    pub async fn generate_group(
+        &self,
+        state: &State,
+        port: &DbSyncPort,
+        seed: &str,
+    ) -> Result<String, Error> {
+        let ports = state.ports.list(state).await?;
+        self.generate(seed, |candidate| {
+            let taken = claimed_by_other(&ports, port, &format!("{candidate}_group"));
+            async move { Ok(taken) }
+        })
+        .await
+    }
+}
+
+// A role in the status of another port. A port recreated with the same name is the same port.
+fn claimed_by_other(ports: &[Arc<DbSyncPort>], port: &DbSyncPort, role: &str) -> bool {
+    ports
+        .iter()
+        .filter(|other| {
+            other.namespace() != port.namespace() || other.name_any() != port.name_any()
+        })
+        .filter_map(|other| Some((other, other.status.as_ref()?)))
+        .any(|(other, status)| status.usernames().contains(&role) || other.group(status) == role)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::collections::HashSet;
+
+    fn bech32_truncated(seed: &str) -> String {
+        let hash = Sha3_256::digest(seed.as_bytes());
+        let encoded =
+            bech32::encode("dmtr_dbsync", hash.to_base32(), bech32::Variant::Bech32).unwrap();
+        encoded.chars().take(32).collect()
+    }
+
+    #[test]
+    fn test_candidate() {
+        let generator = UsernameGenerator::new("dmtr_dbsync", 32).unwrap();
+
+        let username = generator.candidate("port.prj-test", 0);
+        assert_eq!(username.len(), 32);
+        assert_eq!(username, generator.candidate("port.prj-test", 0));
+        assert_eq!(username, bech32_truncated("port.prj-test"));
+        assert_ne!(username, generator.candidate("port.prj-test", 1));
+        assert_ne!(username, generator.candidate("port.prj-other", 0));
+    }
+
+    #[test]
+    fn test_truncation() {
+        let generator = UsernameGenerator::new("dmtr", 13).unwrap();
+        let username = generator.candidate("port.prj-test", 0);
+        assert_eq!(username.len(), 13);
+        assert!(username.starts_with("dmtr1"));
+
+        // The hash has 52 characters, longer names aren't padded
+        let generator = UsernameGenerator::new("dmtr", 63).unwrap();
+        assert_eq!(generator.candidate("port.prj-test", 0).len(), 57);
+
+        assert!(UsernameGenerator::new("dmtr", 12).is_err());
+        assert!(UsernameGenerator::new("dmtr", 64).is_err());
+        assert!(UsernameGenerator::new("Dmtr", 32).is_err());
+        assert!(UsernameGenerator::new("1dmtr", 32).is_err());
+    }
+
+    #[test]
+    fn test_claimed_by_other() {
+        let port = fixtures::port("a", "cardano-mainnet");
+        let ports = vec![
+            Arc::new(fixtures::provisioned(
+                port.clone(),
+                &["dmtr_dbsync1a", "dmtr_dbsync1a2"],
+            )),
+            Arc::new(fixtures::provisioned(
+                fixtures::port("b", "cardano-mainnet"),
+                &["dmtr_dbsync1b"],
+            )),
+        ];
+
+        // The roles of the port itself, e.g. of a stale status, are a match
+        assert!(!claimed_by_other(&ports, &port, "dmtr_dbsync1a"));
+        assert!(!claimed_by_other(&ports, &port, "dmtr_dbsync1a_group"));
+
+        assert!(claimed_by_other(&ports, &port, "dmtr_dbsync1b"));
+        assert!(claimed_by_other(&ports, &port, "dmtr_dbsync1b_group"));
+        assert!(!claimed_by_other(&ports, &port, "dmtr_dbsync1c"));
+    }
+
+    #[tokio::test]
+    async fn test_generate_collision() {
+        let generator = UsernameGenerator::new("dmtr_dbsync", 32).unwrap();
+        let first = generator.candidate("port.prj-test", 0);
+        let second = generator.candidate("port.prj-test", 1);
+
+        let taken = HashSet::from([first.clone()]);
+        let username = generator
+            .generate("port.prj-test", |candidate| {
+                let taken = taken.contains(&candidate);
+                async move { Ok(taken) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(username, second);
+
+        let username = generator
+            .generate("port.prj-test", |_| async { Ok(false) })
+            .await
+            .unwrap();
+        assert_eq!(username, first);
+
+        let result = generator
+            .generate("port.prj-test", |_| async { Ok(true) })
+            .await;
+        assert!(result.is_err());
+    }
+}
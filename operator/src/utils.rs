@@ -15,3 +15,32 @@ pub fn handle_legacy_networks(network: &str) -> String {
     let default = network.to_string();
     LEGACY_NETWORKS.get(network).unwrap_or(&default).to_string()
 }
+
+// The legacy names are aliases of the networks configured with DB_NAMES
+pub fn legacy_aliases(network: &str) -> Vec<String> {
+    let mut aliases: Vec<String> = LEGACY_NETWORKS
+        .iter()
+        .filter(|(_, canonical)| canonical.as_str() == network)
+        .map(|(alias, _)| alias.to_string())
+        .collect();
+    aliases.sort();
+    aliases
+}
+
+pub async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to listen to SIGTERM");
+        tokio::select! {
+            _ = ctrl_c => {},
+            _ = terminate.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = ctrl_c.await;
+}
@@ -0,0 +1,51 @@
+use kube::CustomResource;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    controller::{network_schema, username_schema, MAX_THROUGHPUT_TIER, MIN_PASSWORD_LENGTH},
+    DbSyncPortStatus,
+};
+
+// Served for the clients that still use it, the objects are stored as v1beta1 and converted by
+// the webhook in conversion.rs.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(
+    kind = "DbSyncPort",
+    group = "demeter.run",
+    version = "v1alpha1",
+    category = "demeter-port",
+    shortname = "dbsp",
+    namespaced
+)]
+#[kube(status = "DbSyncPortStatus", derive = "PartialEq")]
+#[kube(printcolumn = r#"
+        {"name": "Network", "jsonPath": ".spec.network", "type": "string"},
+        {"name": "Throughput Tier", "jsonPath":".spec.throughputTier", "type": "string"}, 
+        {"name": "Username", "jsonPath": ".status.username",  "type": "string"},
+        {"name": "Password", "jsonPath": ".status.password", "type": "string"},
+        {"name": "Synced", "jsonPath": ".status.conditions[?(@.type==\"ChainSynced\")].status", "type": "string"}
+    "#)]
+#[serde(rename_all = "camelCase")]
+pub struct DbSyncPortSpec {
+    #[schemars(schema_with = "network_schema")]
+    pub network: String,
+    #[serde(default)]
+    #[schemars(schema_with = "throughput_tier_schema")]
+    pub throughput_tier: Option<String>,
+    #[serde(default)]
+    #[schemars(schema_with = "username_schema")]
+    pub username: Option<String>,
+    #[schemars(length(min = "MIN_PASSWORD_LENGTH"))]
+    pub password: Option<String>,
+}
+
+fn throughput_tier_schema(_: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(json!({
+        "type": "string",
+        "nullable": true,
+        "pattern": format!("^[0-{MAX_THROUGHPUT_TIER}]$"),
+    }))
+    .unwrap()
+}